@@ -0,0 +1,175 @@
+//! Measures how fast a large generated source file is tokenized, the way
+//! tables and unrolled code are, against the tokenizer as it was before it
+//! read its input whole, which read the file a byte at a time through an
+//! unbuffered `Bytes<File>`. Run with `cargo bench`.
+
+extern crate c8asm;
+
+use std::env;
+use std::fmt::Write;
+use std::fs::{self, File};
+use std::process;
+use std::time::{Duration, Instant};
+
+use c8asm::error::Errors;
+use c8asm::parser::{tokenize, Stream, TokenKind};
+
+const LINES: usize = 100_000;
+const RUNS: usize = 3;
+
+fn main() {
+    let source = generate(LINES);
+    let megabytes = source.len() as f64 / 1e6;
+    let path = env::temp_dir().join(format!("c8asm-bench-{}.asm", process::id()));
+    fs::write(&path, &source).unwrap();
+
+    // Both return the number of tokens and the sum of the numbers read
+    let ((count, sum), tokenizer) = best_of(|| {
+        let mut errors = Errors::new();
+        let tokens = tokenize(&mut Stream::new(File::open(&path).unwrap()), &mut errors);
+        assert!(errors.is_empty());
+        let sum = tokens.iter().map(|token| match token.kind {
+            TokenKind::ImmConst(n) => n,
+            _ => 0,
+        }).sum::<i64>();
+        (tokens.len(), sum)
+    });
+    let ((baseline_count, baseline_sum), baseline) = best_of(|| {
+        let mut stream = baseline::Stream::new(File::open(&path).unwrap());
+        let (mut count, mut sum) = (0, 0);
+        while let Some(token) = stream.next_token() {
+            count += 1;
+            if let baseline::Token::ImmConst(n) = token {
+                sum += n as i64;
+            }
+        }
+        (count, sum)
+    });
+    fs::remove_file(&path).unwrap();
+    // The baseline knows no commas, so the tokenizer reads one more token
+    // for each of them
+    assert_eq!((count - source.matches(',').count(), sum), (baseline_count, baseline_sum));
+
+    println!("tokenized {} lines ({} tokens, {:.1} MB) from a file", LINES, count, megabytes);
+    println!("tokenizer: {:>8.1} ms, {:>6.1} MB/s", tokenizer * 1e3, megabytes / tokenizer);
+    println!("baseline:  {:>8.1} ms, {:>6.1} MB/s", baseline * 1e3, megabytes / baseline);
+    println!("the tokenizer is {:.1}x as fast as the baseline", baseline / tokenizer);
+}
+
+// Runs `f` a few times and returns its result along with its fastest time,
+// in seconds
+fn best_of<T, F: FnMut() -> T>(mut f: F) -> (T, f64) {
+    let mut best: Option<(T, Duration)> = None;
+    for _ in 0..RUNS {
+        let start = Instant::now();
+        let result = f();
+        let elapsed = start.elapsed();
+        if best.as_ref().is_none_or(|&(_, time)| elapsed < time) {
+            best = Some((result, elapsed));
+        }
+    }
+    let (result, time) = best.unwrap();
+    (result, time.as_secs_f64())
+}
+
+// Instructions, numbers and comments, which both tokenizers read
+fn generate(lines: usize) -> String {
+    let mut source = String::new();
+    for i in 0..lines {
+        match i % 8 {
+            0 => writeln!(source, "    LD V{:X}, {:#04x}", i % 16, i % 256),
+            1 => writeln!(source, "    ADD V{:X}, {}", i % 16, i % 256),
+            2 => writeln!(source, "    DRW V0, V1, {} ; draw the row", i % 16),
+            3 => writeln!(source, "    SE V{:X}, V{:X}", i % 16, (i + 1) % 16),
+            4 => writeln!(source, "    JP {:#05x}", 0x200 + i % 0xe00),
+            5 => writeln!(source, "    LD I, {:#05x}", 0x200 + i % 0xe00),
+            6 => writeln!(source, "    SKP V{:X}", i % 16),
+            _ => writeln!(source, "    LD DT, V{:X} ; wait", i % 16),
+        }.unwrap();
+    }
+    source
+}
+
+// The tokenizer of the first release, as it read files: every byte is read
+// with its own call on the file, as `Bytes<File>` does, and every word is
+// copied into a lowercased `String` before it is classified
+mod baseline {
+    use std::fs::File;
+    use std::io::Read;
+
+    const COMMENT_CHAR: char = ';';
+    const NEWLINE_CHAR: char = '\n';
+
+    const MNEMONICS: [&str; 20] = [
+        "add", "and", "call", "cls", "drw", "jp", "ld", "or", "ret", "rnd",
+        "se", "shl", "shr", "sknp", "skp", "sne", "sub", "subn", "sys", "xor",
+    ];
+    const REGISTERS: [&str; 20] = [
+        "v0", "v1", "v2", "v3", "v4", "v5", "v6", "v7", "v8", "v9",
+        "va", "vb", "vc", "vd", "ve", "vf", "st", "dt", "i", "[i]",
+    ];
+
+    pub enum Token {
+        Opcode,
+        Reg,
+        ImmConst(u16),
+        Comment,
+    }
+
+    pub struct Stream {
+        input: File,
+    }
+
+    impl Stream {
+        pub fn new(input: File) -> Stream {
+            Stream { input }
+        }
+
+        fn next_byte(&mut self) -> Option<u8> {
+            let mut byte = [0];
+            match self.input.read(&mut byte) {
+                Ok(1) => Some(byte[0]),
+                _ => None,
+            }
+        }
+
+        pub fn next_token(&mut self) -> Option<Token> {
+            let mut buffer: Vec<u8> = vec![];
+            loop {
+                match self.next_byte() {
+                    Some(b) if Stream::is_separator(b) => {
+                        if !buffer.is_empty() {
+                            return Stream::create_token(&buffer);
+                        }
+                    },
+                    Some(b) if b as char == COMMENT_CHAR => loop {
+                        match self.next_byte() {
+                            Some(b) if b as char != NEWLINE_CHAR => {},
+                            _ => return Some(Token::Comment),
+                        }
+                    },
+                    Some(b) => buffer.push(b),
+                    None => return Stream::create_token(&buffer),
+                }
+            }
+        }
+
+        fn create_token(input: &[u8]) -> Option<Token> {
+            let token = String::from_utf8(input.to_vec()).unwrap_or_default().to_lowercase();
+            if let Some(hex) = token.strip_prefix("0x") {
+                return u16::from_str_radix(hex, 16).ok().map(Token::ImmConst);
+            }
+            if token.starts_with(|c: char| c.is_ascii_digit()) {
+                return token.parse().ok().map(Token::ImmConst);
+            }
+            if REGISTERS.contains(&token.as_str()) {
+                return Some(Token::Reg);
+            }
+            MNEMONICS.contains(&token.as_str()).then_some(Token::Opcode)
+        }
+
+        fn is_separator(b: u8) -> bool {
+            matches!(b as char, ',' | ' ' | '\n' | '\t')
+        }
+    }
+}
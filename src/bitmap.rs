@@ -0,0 +1,238 @@
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+/// Largest sprite `DRW` can draw in Chip-8 mode, 8 pixels wide
+pub const MAX_SPRITE_HEIGHT: usize = 15;
+/// Side of the square sprites `DRW Vx, Vy, 0` draws in SUPER-CHIP mode
+pub const LARGE_SPRITE_SIZE: usize = 16;
+
+/// Errors reported while reading an image or cutting a sprite out of it
+#[derive(Debug)]
+pub enum BitmapError {
+    /// A file that is not a PBM or PGM image or is cut short
+    Malformed(String),
+    /// A region that does not lie entirely within the image
+    OutOfBounds { x: usize, y: usize, width: usize, height: usize },
+    /// A region too large to be drawn as a sprite
+    TooLarge { width: usize, height: usize },
+    Io(io::Error),
+}
+
+impl fmt::Display for BitmapError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            BitmapError::Malformed(ref reason) => write!(f, "malformed image: {}", reason),
+            BitmapError::OutOfBounds { x, y, width, height } => {
+                write!(f, "region of {}x{} pixels at ({}, {}) lies outside the image", width, height, x, y)
+            },
+            BitmapError::TooLarge { width, height } => {
+                write!(f, "sprite of {}x{} pixels cannot be drawn", width, height)
+            },
+            BitmapError::Io(ref err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl Error for BitmapError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match *self {
+            BitmapError::Io(ref err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for BitmapError {
+    fn from(err: io::Error) -> BitmapError {
+        BitmapError::Io(err)
+    }
+}
+
+/// A picture whose pixels are either set or clear, such as a sprite sheet
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Bitmap {
+    width: usize,
+    height: usize,
+    // Row after row, `true` for a set pixel
+    pixels: Vec<bool>,
+}
+
+impl Bitmap {
+    /// A bitmap with every pixel clear
+    pub fn new(width: usize, height: usize) -> Bitmap {
+        Bitmap { width, height, pixels: vec![false; width * height] }
+    }
+
+    /// Reads a netpbm image: a PBM bitmap (P1 or P4) whose black pixels are
+    /// set, or a PGM graymap (P2 or P5) whose pixels darker than half the
+    /// maximum value are set
+    pub fn from_netpbm(data: &[u8]) -> Result<Bitmap, BitmapError> {
+        let mut header = Header { data, pos: 0 };
+        let magic = header.magic()?;
+        let width = header.number("width")?;
+        let height = header.number("height")?;
+        let max = match magic {
+            b'2' | b'5' => header.number("maximum value")?,
+            _ => 1,
+        };
+        if max == 0 || max > 0xffff {
+            return Err(BitmapError::Malformed(format!("maximum value {} is out of range", max)));
+        }
+
+        // Gray levels below half the maximum count as black
+        let threshold = max.div_ceil(2);
+        // Every format takes at least a bit per pixel
+        let len = width.checked_mul(height).filter(|&len| len <= data.len() * 8).ok_or_else(too_short)?;
+        let mut bitmap = Bitmap::new(width, height);
+        match magic {
+            b'1' => {
+                let mut digits = data[header.pos..].iter().filter(|b| !b.is_ascii_whitespace());
+                for pixel in &mut bitmap.pixels {
+                    *pixel = match digits.next() {
+                        Some(b'0') => false,
+                        Some(b'1') => true,
+                        Some(&b) => return Err(BitmapError::Malformed(format!("unexpected `{}`", b as char))),
+                        None => return Err(too_short()),
+                    };
+                }
+            },
+            b'2' => {
+                for pixel in &mut bitmap.pixels {
+                    *pixel = header.number("pixel")? < threshold;
+                }
+            },
+            b'4' => {
+                let row_bytes = width.div_ceil(8);
+                let raster = header.raster(row_bytes * height)?;
+                for (i, pixel) in bitmap.pixels.iter_mut().enumerate() {
+                    let (x, y) = (i % width, i / width);
+                    *pixel = raster[y * row_bytes + x / 8] & (0x80 >> (x % 8)) != 0;
+                }
+            },
+            _ => {
+                let size = if max > 0xff { 2 } else { 1 };
+                let raster = header.raster(len * size)?;
+                for (pixel, sample) in bitmap.pixels.iter_mut().zip(raster.chunks(size)) {
+                    let value = sample.iter().fold(0, |value, &b| (value << 8) | b as usize);
+                    *pixel = value < threshold;
+                }
+            },
+        }
+        Ok(bitmap)
+    }
+
+    /// Reads a netpbm image from a file
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Bitmap, BitmapError> {
+        Bitmap::from_netpbm(&fs::read(path)?)
+    }
+
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
+    /// Whether the pixel at `(x, y)` is set. Pixels outside the bitmap are
+    /// clear.
+    pub fn get(&self, x: usize, y: usize) -> bool {
+        x < self.width && y < self.height && self.pixels[y * self.width + x]
+    }
+
+    pub fn set(&mut self, x: usize, y: usize, value: bool) {
+        if x < self.width && y < self.height {
+            self.pixels[y * self.width + x] = value;
+        }
+    }
+
+    /// Encodes a region of the bitmap as the bytes of a sprite, one byte per
+    /// row with the leftmost pixel in the most significant bit. A region up
+    /// to 8 pixels wide and 15 rows high makes a Chip-8 sprite, a region 16
+    /// rows high and up to 16 pixels wide a SUPER-CHIP sprite with two bytes
+    /// per row. Rows narrower than their bytes are padded with clear pixels.
+    pub fn sprite(&self, x: usize, y: usize, width: usize, height: usize) -> Result<Vec<u8>, BitmapError> {
+        let row_bytes = match (width, height) {
+            (1..=8, 1..=MAX_SPRITE_HEIGHT) => 1,
+            (1..=LARGE_SPRITE_SIZE, LARGE_SPRITE_SIZE) => 2,
+            _ => return Err(BitmapError::TooLarge { width, height }),
+        };
+        if x.saturating_add(width) > self.width || y.saturating_add(height) > self.height {
+            return Err(BitmapError::OutOfBounds { x, y, width, height });
+        }
+        let mut bytes = Vec::with_capacity(row_bytes * height);
+        for row in y..y + height {
+            for byte in 0..row_bytes {
+                let bits = (0..8).fold(0u8, |bits, bit| {
+                    let column = byte * 8 + bit;
+                    (bits << 1) | (column < width && self.get(x + column, row)) as u8
+                });
+                bytes.push(bits);
+            }
+        }
+        Ok(bytes)
+    }
+}
+
+/// Reads a netpbm image and encodes the given region of it as a sprite,
+/// the bytes `INCSPRITE` emits
+pub fn load_sprite<P: AsRef<Path>>(path: P, x: usize, y: usize, width: usize, height: usize)
+    -> Result<Vec<u8>, BitmapError>
+{
+    Bitmap::open(path)?.sprite(x, y, width, height)
+}
+
+fn too_short() -> BitmapError {
+    BitmapError::Malformed("pixel data is cut short".to_owned())
+}
+
+// Reads the header of a netpbm image, and the pixels of plain formats
+struct Header<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Header<'a> {
+    // The digit of the `P1`, `P2`, `P4` or `P5` magic number
+    fn magic(&mut self) -> Result<u8, BitmapError> {
+        match *self.data {
+            [b'P', n @ (b'1' | b'2' | b'4' | b'5'), ..] => {
+                self.pos = 2;
+                Ok(n)
+            },
+            _ => Err(BitmapError::Malformed("expected a PBM or PGM image".to_owned())),
+        }
+    }
+
+    // A decimal number, skipping the whitespace and comments before it
+    fn number(&mut self, what: &str) -> Result<usize, BitmapError> {
+        loop {
+            match self.data.get(self.pos) {
+                Some(b) if b.is_ascii_whitespace() => self.pos += 1,
+                Some(b'#') => {
+                    while self.data.get(self.pos).is_some_and(|&b| b != b'\n') {
+                        self.pos += 1;
+                    }
+                },
+                _ => break,
+            }
+        }
+        let start = self.pos;
+        while self.data.get(self.pos).is_some_and(u8::is_ascii_digit) {
+            self.pos += 1;
+        }
+        let digits = &self.data[start..self.pos];
+        std::str::from_utf8(digits).ok().and_then(|digits| digits.parse().ok()).ok_or_else(|| {
+            BitmapError::Malformed(format!("expected the {}", what))
+        })
+    }
+
+    // The binary pixel data, which follows a single whitespace character
+    fn raster(&self, len: usize) -> Result<&'a [u8], BitmapError> {
+        let start = self.pos + 1;
+        self.data.get(start..start + len).ok_or_else(too_short)
+    }
+}
@@ -0,0 +1,154 @@
+use std::fmt::Write;
+
+use error::AssembleError;
+use parser::Span;
+
+const RED: &str = "\x1b[1;31m";
+const YELLOW: &str = "\x1b[1;33m";
+const BLUE: &str = "\x1b[1;34m";
+const BOLD: &str = "\x1b[1m";
+const RESET: &str = "\x1b[0m";
+
+/// Formats errors the way rustc does: the message, the location, the
+/// offending source line with the erroneous token underlined and a hint.
+///
+/// ```text
+/// error: unexpected operand `K` for DRW
+///  --> game.asm:6:15
+///   |
+/// 6 |     DRW V0, V1, K
+///   |                 ^
+///   = hint: DRW expects Vx, Vy, nibble
+/// ```
+pub struct Renderer {
+    color: bool,
+}
+
+impl Renderer {
+    pub fn new(color: bool) -> Renderer {
+        Renderer { color }
+    }
+
+    /// Renders `err`, found in the file `name` whose contents are `source`
+    pub fn render(&self, err: &AssembleError, name: &str, source: &str) -> String {
+        self.render_as("error", RED, err, name, source)
+    }
+
+    /// Renders `err` as a warning rather than an error
+    pub fn render_warning(&self, err: &AssembleError, name: &str, source: &str) -> String {
+        self.render_as("warning", YELLOW, err, name, source)
+    }
+
+    /// Renders a note pointing at `span`, which explains where a previous
+    /// diagnostic comes from, e.g. the use of a macro whose body has an error
+    pub fn render_note(&self, message: &str, span: Span, name: &str, source: &str) -> String {
+        let mut out = String::new();
+        let _ = writeln!(out, "{}note{}: {}", self.paint(BOLD), self.reset(), message);
+        self.snippet(&mut out, BOLD, span, name, source);
+        out
+    }
+
+    fn render_as(&self, level: &str, color: &'static str, err: &AssembleError, name: &str, source: &str) -> String {
+        let mut out = String::new();
+        let _ = writeln!(out, "{}{}{}{}: {}{}", self.paint(color), level, self.reset(), self.paint(BOLD),
+                         err.message(), self.reset());
+
+        let span = match err.span() {
+            Some(span) => span,
+            None => return out,
+        };
+        let gutter = self.snippet(&mut out, color, span, name, source);
+
+        if let Some(hint) = err.hint() {
+            let _ = writeln!(out, "{} {}={} {}hint{}: {}", gutter, self.paint(BLUE), self.reset(),
+                             self.paint(BOLD), self.reset(), hint);
+        }
+        out
+    }
+
+    // Writes the location of `span` and its source line with the span
+    // underlined, returning the blank gutter for lines that follow
+    fn snippet(&self, out: &mut String, color: &'static str, span: Span, name: &str, source: &str) -> String {
+        let line = source_lines(source).nth((span.line as usize).saturating_sub(1)).unwrap_or("");
+        let number = span.line.to_string();
+        let gutter = " ".repeat(number.len());
+
+        let _ = writeln!(out, "{}{}-->{} {}:{}", gutter, self.paint(BLUE), self.reset(), name, span);
+        let _ = writeln!(out, "{} {}|{}", gutter, self.paint(BLUE), self.reset());
+        let _ = writeln!(out, "{}{} |{} {}", self.paint(BLUE), number, self.reset(), line);
+
+        // Tabs are kept so the underline lines up with the source line.
+        // Columns count characters, so the underline covers the characters
+        // that lie within the bytes of the span.
+        let column = (span.column as usize).saturating_sub(1);
+        let padding: String = line.chars()
+            .take(column)
+            .map(|c| if c == '\t' { '\t' } else { ' ' })
+            .collect();
+        let mut bytes = 0;
+        let width = line.chars().skip(column).take_while(|c| {
+            bytes += c.len_utf8();
+            bytes <= span.len()
+        }).count();
+        let underline = "^".repeat(width.max(1));
+        let _ = writeln!(out, "{} {}|{} {}{}{}{}", gutter, self.paint(BLUE), self.reset(),
+                         padding, self.paint(color), underline, self.reset());
+        gutter
+    }
+
+    fn paint(&self, code: &'static str) -> &'static str {
+        if self.color { code } else { "" }
+    }
+
+    fn reset(&self) -> &'static str {
+        self.paint(RESET)
+    }
+}
+
+/// Splits source into lines the way the tokenizer does: they end with `\n`,
+/// `\r\n` or a lone `\r`, and a byte order mark at the start is not part of
+/// the first.
+pub fn source_lines(source: &str) -> impl Iterator<Item = &str> {
+    let source = source.strip_prefix('\u{feff}').unwrap_or(source);
+    source.split('\n').flat_map(|line| line.strip_suffix('\r').unwrap_or(line).split('\r'))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Renders the first error found in `source`
+    fn render(source: &str, color: bool) -> String {
+        let errors = ::assemble_str(source).unwrap_err();
+        let err = errors.iter().next().unwrap();
+        Renderer::new(color).render(err, "in.asm", source)
+    }
+
+    #[test]
+    fn renders_snippets() {
+        let out = render("CLS\n\tDB \"\u{e9}\", ??\n", false);
+        assert_eq!(out, "error: invalid token `??`\n \
+                         --> in.asm:2:10\n  \
+                         |\n\
+                         2 | \tDB \"\u{e9}\", ??\n  \
+                         | \t        ^^\n");
+
+        // The underline is as wide as the characters of the token
+        let out = render("LD V0, \u{e9}\u{e9}\n", false);
+        assert!(out.ends_with("1 | LD V0, \u{e9}\u{e9}\n  |        ^^\n"), "{}", out);
+    }
+
+    #[test]
+    fn renders_hints_and_colors() {
+        let source = "DRW V0, V1, K\n";
+        let errors = ::assemble_str(source).unwrap_err();
+        let hint = errors.iter().next().and_then(AssembleError::hint).unwrap();
+        let out = render(source, false);
+        assert!(out.ends_with(&format!("  |             ^\n  = hint: {}\n", hint)), "{}", out);
+        assert!(!out.contains('\x1b'));
+
+        let out = render(source, true);
+        assert!(out.starts_with(&format!("{}error{}", RED, RESET)));
+        assert!(out.contains(&format!("{}^{}", RED, RESET)));
+    }
+}
@@ -0,0 +1,452 @@
+use std::error::Error;
+use std::fmt;
+use std::io;
+
+use bitmap::BitmapError;
+use parser::{Files, Mnemonic, Span, MAX_EXPANDED_TOKENS, MAX_EXPANSIONS, MAX_MACRO_DEPTH, MAX_SYMBOL_DEPTH};
+
+/// Errors reported while assembling a program
+#[derive(Debug)]
+pub enum AssembleError {
+    /// A word that is not a mnemonic appears at the start of an instruction
+    UnknownMnemonic { name: String, span: Span },
+    /// A word that cannot be turned into a token
+    InvalidToken { text: String, span: Span },
+    /// A number or character literal that is malformed or too large
+    InvalidLiteral { text: String, span: Span },
+    /// Bytes of the source that are not UTF-8
+    InvalidUtf8 { span: Span },
+    /// An operand that is not accepted by the instruction
+    WrongOperand { mnemonic: Mnemonic, found: String, span: Span },
+    /// A numeric operand that does not fit in the instruction
+    OperandOutOfRange { value: i64, min: i64, max: i64, span: Span },
+    /// An instruction that ended before all its operands were given
+    MissingOperand { mnemonic: Mnemonic, span: Span },
+    /// An operand following an already complete instruction
+    TrailingOperand { found: String, span: Span },
+    /// A reference to a label that is never defined
+    UndefinedSymbol { name: String, span: Span },
+    /// A label or constant that is defined more than once. `previous` is
+    /// `None` when the first definition is predefined in the options.
+    DuplicateSymbol { name: String, span: Span, previous: Option<Span> },
+    /// A constant whose value depends on itself
+    RecursiveSymbol { name: String, span: Span },
+    /// A constant resolved through more constants than can be nested
+    SymbolTooDeep { name: String, span: Span },
+    /// An expression that is cut short or starts with something that is
+    /// not a value
+    ExpectedExpression { found: String, span: Span },
+    /// A parenthesis that is opened but never closed
+    UnclosedParen { span: Span },
+    /// A division or remainder by zero in an expression
+    DivisionByZero { span: Span },
+    /// An expression whose value is too large to compute
+    Overflow { span: Span },
+    /// A sprite row whose width differs from the first row
+    SpriteRowWidth { expected: usize, found: usize, span: Span },
+    /// A sprite too large to be drawn
+    SpriteSize { width: usize, height: usize, span: Span },
+    /// An image that cannot be read or holds no such sprite
+    Image { path: String, error: BitmapError, span: Span },
+    /// A file that cannot be included
+    ReadFile { path: String, error: io::Error, span: Span },
+    /// A file that includes itself, directly or through other files
+    IncludeCycle { path: String, span: Span },
+    /// A `MACRO` inside the body of another
+    NestedMacro { span: Span },
+    /// A block that is still open at the end of the input, located at its
+    /// opening line
+    UnclosedBlock { mnemonic: Mnemonic, span: Span },
+    /// A directive closing a block that was never opened
+    UnmatchedBlockEnd { mnemonic: Mnemonic, span: Span },
+    /// An `ELSEIF` or `ELSE` following the `ELSE` of its block
+    BranchAfterElse { mnemonic: Mnemonic, span: Span },
+    /// A macro used with a different number of arguments than it has
+    /// parameters
+    MacroArguments { name: String, expected: usize, found: usize, span: Span },
+    /// A macro whose expansions are nested too deeply, usually because it
+    /// uses itself
+    MacroRecursion { name: String, span: Span },
+    /// A macro or repeated block used after the program has expanded as
+    /// many of them, or into as many tokens, as it can
+    TooManyExpansions { span: Span },
+    /// A reference to a local label that is not defined under the global
+    /// label it follows, `scope`. `others` are the names of the local labels
+    /// of that name defined under other global labels.
+    UndefinedLocalLabel { name: String, scope: Option<String>, others: Vec<String>, span: Span },
+    /// A reference to an anonymous label, e.g. `++`, past the last one
+    /// defined in its direction. `found` is the number of labels there are.
+    MissingAnonymousLabel { reference: String, found: usize, span: Span },
+    /// A character of a `.text` string that is not in the character map
+    UnmappedChar { c: char, span: Span },
+    /// An `ORG` to an address below the current location
+    BackwardOrigin { address: u16, current: u16, span: Span },
+    Io(io::Error),
+}
+
+impl AssembleError {
+    /// Location in the source the error refers to, if any. A span on line
+    /// 0, which no token has, is not a location.
+    pub fn span(&self) -> Option<Span> {
+        let span = match *self {
+            AssembleError::UnknownMnemonic { span, .. } |
+            AssembleError::InvalidToken { span, .. } |
+            AssembleError::InvalidLiteral { span, .. } |
+            AssembleError::InvalidUtf8 { span } |
+            AssembleError::WrongOperand { span, .. } |
+            AssembleError::OperandOutOfRange { span, .. } |
+            AssembleError::MissingOperand { span, .. } |
+            AssembleError::TrailingOperand { span, .. } |
+            AssembleError::UndefinedSymbol { span, .. } |
+            AssembleError::DuplicateSymbol { span, .. } |
+            AssembleError::RecursiveSymbol { span, .. } |
+            AssembleError::SymbolTooDeep { span, .. } |
+            AssembleError::ExpectedExpression { span, .. } |
+            AssembleError::UnclosedParen { span } |
+            AssembleError::DivisionByZero { span } |
+            AssembleError::Overflow { span } |
+            AssembleError::SpriteRowWidth { span, .. } |
+            AssembleError::SpriteSize { span, .. } |
+            AssembleError::Image { span, .. } |
+            AssembleError::ReadFile { span, .. } |
+            AssembleError::IncludeCycle { span, .. } |
+            AssembleError::NestedMacro { span } |
+            AssembleError::UnclosedBlock { span, .. } |
+            AssembleError::UnmatchedBlockEnd { span, .. } |
+            AssembleError::MacroArguments { span, .. } |
+            AssembleError::BranchAfterElse { span, .. } |
+            AssembleError::MacroRecursion { span, .. } |
+            AssembleError::TooManyExpansions { span } |
+            AssembleError::UndefinedLocalLabel { span, .. } |
+            AssembleError::MissingAnonymousLabel { span, .. } |
+            AssembleError::UnmappedChar { span, .. } |
+            AssembleError::BackwardOrigin { span, .. } => Some(span),
+            AssembleError::Io(_) => None,
+        };
+        span.filter(|span| span.line > 0)
+    }
+
+    /// Describes the error without its location
+    pub fn message(&self) -> String {
+        match *self {
+            AssembleError::UnknownMnemonic { ref name, .. } => {
+                format!("unknown mnemonic `{}`", name)
+            },
+            AssembleError::InvalidToken { ref text, .. } => {
+                format!("invalid token `{}`", text)
+            },
+            AssembleError::InvalidLiteral { ref text, .. } => {
+                format!("invalid literal `{}`", text)
+            },
+            AssembleError::InvalidUtf8 { .. } => "invalid UTF-8 in source".to_owned(),
+            AssembleError::WrongOperand { ref mnemonic, ref found, .. } => {
+                format!("unexpected operand `{}` for {}", found, mnemonic)
+            },
+            AssembleError::OperandOutOfRange { value, min, max, .. } => {
+                format!("operand {} is out of range, expected a value from {} to {}", value, min, max)
+            },
+            AssembleError::MissingOperand { ref mnemonic, .. } => {
+                format!("missing operand for {}", mnemonic)
+            },
+            AssembleError::TrailingOperand { ref found, .. } => {
+                format!("unexpected trailing operand `{}`", found)
+            },
+            AssembleError::UndefinedSymbol { ref name, .. } => {
+                format!("undefined symbol `{}`", name)
+            },
+            AssembleError::DuplicateSymbol { ref name, .. } => {
+                format!("duplicate symbol `{}`", name)
+            },
+            AssembleError::RecursiveSymbol { ref name, .. } => {
+                format!("constant `{}` is defined in terms of itself", name)
+            },
+            AssembleError::SymbolTooDeep { ref name, .. } => {
+                format!("constant `{}` is nested too deeply", name)
+            },
+            AssembleError::ExpectedExpression { ref found, .. } => {
+                format!("expected an expression, found {}", found)
+            },
+            AssembleError::UnclosedParen { .. } => "unclosed parenthesis".to_owned(),
+            AssembleError::DivisionByZero { .. } => "division by zero".to_owned(),
+            AssembleError::Overflow { .. } => "arithmetic overflow in expression".to_owned(),
+            AssembleError::SpriteRowWidth { expected, found, .. } => {
+                format!("sprite row is {} pixels wide, expected {}", found, expected)
+            },
+            AssembleError::SpriteSize { width, height, .. } => {
+                format!("sprite of {}x{} pixels cannot be drawn", width, height)
+            },
+            AssembleError::Image { ref path, error: BitmapError::OutOfBounds { x, y, width, height }, .. } => {
+                format!("region of {}x{} pixels at ({}, {}) lies outside image `{}`", width, height, x, y, path)
+            },
+            AssembleError::Image { ref path, ref error, .. } => {
+                format!("cannot read image `{}`: {}", path, error)
+            },
+            AssembleError::ReadFile { ref path, ref error, .. } => {
+                format!("cannot read `{}`: {}", path, error)
+            },
+            AssembleError::IncludeCycle { ref path, .. } => {
+                format!("`{}` includes itself", path)
+            },
+            AssembleError::NestedMacro { .. } => "macros cannot be defined inside a macro".to_owned(),
+            AssembleError::UnclosedBlock { ref mnemonic, .. } => {
+                format!("{} block is never closed", mnemonic)
+            },
+            AssembleError::UnmatchedBlockEnd { ref mnemonic, .. } => {
+                format!("{} without a matching block", mnemonic)
+            },
+            AssembleError::BranchAfterElse { ref mnemonic, .. } => {
+                format!("{} after the ELSE of its block", mnemonic)
+            },
+            AssembleError::MacroArguments { ref name, expected, found, .. } => {
+                format!("macro `{}` expects {} arguments, found {}", name, expected, found)
+            },
+            AssembleError::MacroRecursion { ref name, .. } => {
+                format!("macro `{}` expands too deeply", name)
+            },
+            AssembleError::TooManyExpansions { .. } => "too many macro expansions and repetitions".to_owned(),
+            AssembleError::UndefinedLocalLabel { ref name, scope: Some(ref scope), .. } => {
+                format!("local label `{}` is not defined under `{}`", name, scope)
+            },
+            AssembleError::UndefinedLocalLabel { ref name, scope: None, .. } => {
+                format!("local label `{}` is not defined before the first global label", name)
+            },
+            AssembleError::MissingAnonymousLabel { ref reference, found, .. } => {
+                let (label, direction) = if reference.starts_with('+') { ("+:", "after") } else { ("-:", "before") };
+                format!("`{}` needs {} `{}` labels {} it, found {}",
+                        reference, reference.len(), label, direction, found)
+            },
+            AssembleError::UnmappedChar { c, .. } => {
+                format!("character {:?} is not in the character map", c)
+            },
+            AssembleError::BackwardOrigin { address, current, .. } => {
+                format!("ORG {:#05x} moves the location counter backwards from {:#05x}", address, current)
+            },
+            AssembleError::Io(ref err) => err.to_string(),
+        }
+    }
+
+    /// A short suggestion on how to fix the error, if there is one
+    pub fn hint(&self) -> Option<String> {
+        match *self {
+            AssembleError::WrongOperand { ref mnemonic, .. } |
+            AssembleError::MissingOperand { ref mnemonic, .. } => {
+                Some(format!("{} expects {}", mnemonic, mnemonic.operands()))
+            },
+            AssembleError::DuplicateSymbol { ref name, previous: Some(previous), .. } => {
+                Some(format!("`{}` was first defined at {}", name, previous))
+            },
+            AssembleError::SpriteSize { .. } => {
+                Some("sprites are up to 8 pixels wide and 15 rows high, or 16x16 for SUPER-CHIP".to_owned())
+            },
+            AssembleError::ReadFile { ref error, .. } if error.kind() == io::ErrorKind::NotFound => {
+                Some("files are searched next to the file naming them, then in the directories given with -I"
+                     .to_owned())
+            },
+            AssembleError::UnclosedBlock { ref mnemonic, .. } => {
+                mnemonic.closing().map(|closing| format!("add {} after the block", closing))
+            },
+            AssembleError::MacroRecursion { .. } => {
+                Some(format!("macro expansions can be nested up to {} levels", MAX_MACRO_DEPTH))
+            },
+            AssembleError::TooManyExpansions { .. } => {
+                Some(format!("a program can expand macros and repeat blocks up to {} times and into {} tokens in all",
+                             MAX_EXPANSIONS, MAX_EXPANDED_TOKENS))
+            },
+            AssembleError::SymbolTooDeep { .. } => {
+                Some(format!("constants can be defined in terms of each other up to {} levels", MAX_SYMBOL_DEPTH))
+            },
+            AssembleError::DuplicateSymbol { ref name, previous: None, .. } => {
+                Some(format!("`{}` is predefined", name))
+            },
+            AssembleError::InvalidUtf8 { .. } => Some("source files must be encoded in UTF-8".to_owned()),
+            AssembleError::UnmappedChar { c, .. } => {
+                Some(format!("map it with `.charmap {:?}, value`", c))
+            },
+            AssembleError::UndefinedLocalLabel { ref others, .. } if others.len() == 1 => {
+                Some(format!("did you mean `{}`?", others[0]))
+            },
+            AssembleError::UndefinedLocalLabel { ref name, ref others, .. } if !others.is_empty() => {
+                let others: Vec<String> = others.iter().map(|other| format!("`{}`", other)).collect();
+                Some(format!("`{}` is ambiguous here, refer to one of {}", name, others.join(", ")))
+            },
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for AssembleError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let Some(span) = self.span() {
+            write!(f, "{}: ", span)?;
+        }
+        write!(f, "{}", self.message())?;
+        if let Some(hint) = self.hint() {
+            write!(f, " ({})", hint)?;
+        }
+        Ok(())
+    }
+}
+
+impl Error for AssembleError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match *self {
+            AssembleError::Image { ref error, .. } => Some(error),
+            AssembleError::ReadFile { ref error, .. } => Some(error),
+            AssembleError::Io(ref err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+/// Errors found while splitting the source into tokens
+#[derive(Debug)]
+pub enum LexError {
+    /// A word that cannot be turned into a token
+    InvalidToken { text: String, span: Span },
+    /// A number or character literal that is malformed or too large
+    InvalidLiteral { text: String, span: Span },
+    /// Bytes that are not UTF-8
+    InvalidUtf8 { span: Span },
+    /// Input that cannot be read
+    Io(io::Error),
+}
+
+impl LexError {
+    /// Location in the source the error refers to, if any
+    pub fn span(&self) -> Option<Span> {
+        match *self {
+            LexError::InvalidToken { span, .. } |
+            LexError::InvalidLiteral { span, .. } |
+            LexError::InvalidUtf8 { span } => Some(span),
+            LexError::Io(_) => None,
+        }
+    }
+}
+
+impl fmt::Display for LexError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let Some(span) = self.span() {
+            write!(f, "{}: ", span)?;
+        }
+        match *self {
+            LexError::InvalidToken { ref text, .. } => write!(f, "invalid token `{}`", text),
+            LexError::InvalidLiteral { ref text, .. } => write!(f, "invalid literal `{}`", text),
+            LexError::InvalidUtf8 { .. } => write!(f, "invalid UTF-8 in source"),
+            LexError::Io(ref err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl Error for LexError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match *self {
+            LexError::Io(ref err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<LexError> for AssembleError {
+    fn from(err: LexError) -> AssembleError {
+        match err {
+            LexError::InvalidToken { text, span } => AssembleError::InvalidToken { text, span },
+            LexError::InvalidLiteral { text, span } => AssembleError::InvalidLiteral { text, span },
+            LexError::InvalidUtf8 { span } => AssembleError::InvalidUtf8 { span },
+            LexError::Io(err) => AssembleError::Io(err),
+        }
+    }
+}
+
+impl From<io::Error> for AssembleError {
+    fn from(err: io::Error) -> AssembleError {
+        AssembleError::Io(err)
+    }
+}
+
+/// Every error found while assembling a program
+#[derive(Debug, Default)]
+pub struct Errors {
+    errors: Vec<AssembleError>,
+}
+
+impl Errors {
+    pub fn new() -> Errors {
+        Errors { errors: vec![] }
+    }
+
+    pub fn push(&mut self, err: AssembleError) {
+        self.errors.push(err);
+    }
+
+    pub fn extend(&mut self, other: Errors) {
+        self.errors.extend(other.errors);
+    }
+
+    /// Orders the errors by their position in the source and drops those
+    /// reported more than once, e.g. through every use of a bad constant
+    pub fn sort(&mut self) {
+        self.errors.sort_by_key(|err| err.span());
+        self.dedup();
+    }
+
+    /// Orders the errors like `sort`, except that those in an included file
+    /// come where the file is included rather than after the input
+    pub fn sort_by_origin(&mut self, files: &Files) {
+        self.errors.sort_by_cached_key(|err| err.span().map(|span| files.origin(span)));
+        self.dedup();
+    }
+
+    fn dedup(&mut self) {
+        self.errors.dedup_by(|a, b| a.span() == b.span() && a.message() == b.message());
+    }
+
+    pub fn len(&self) -> usize {
+        self.errors.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.errors.is_empty()
+    }
+
+    pub fn iter(&self) -> ::std::slice::Iter<'_, AssembleError> {
+        self.errors.iter()
+    }
+}
+
+impl fmt::Display for Errors {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for err in &self.errors {
+            writeln!(f, "{}", err)?;
+        }
+        match self.errors.len() {
+            1 => write!(f, "1 error"),
+            n => write!(f, "{} errors", n),
+        }
+    }
+}
+
+impl Error for Errors {}
+
+impl From<AssembleError> for Errors {
+    fn from(err: AssembleError) -> Errors {
+        Errors { errors: vec![err] }
+    }
+}
+
+impl<'a> IntoIterator for &'a Errors {
+    type Item = &'a AssembleError;
+    type IntoIter = ::std::slice::Iter<'a, AssembleError>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.errors.iter()
+    }
+}
+
+impl IntoIterator for Errors {
+    type Item = AssembleError;
+    type IntoIter = ::std::vec::IntoIter<AssembleError>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.errors.into_iter()
+    }
+}
@@ -0,0 +1,204 @@
+use std::convert::TryFrom;
+use std::fmt;
+
+use error::AssembleError;
+use parser::Span;
+
+/// An arithmetic or bitwise operator
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Operator {
+    Plus, Minus, Star,
+    Slash, Percent, Amp,
+    Pipe, Caret, Tilde,
+    Shl, Shr,
+    Eq, Ne, Lt,
+    Le, Gt, Ge,
+}
+
+impl Operator {
+    /// Binding power of the operator between two operands, higher binds
+    /// tighter. `None` for operators that only take one operand.
+    pub fn precedence(&self) -> Option<u8> {
+        match *self {
+            Operator::Pipe => Some(1),
+            Operator::Caret => Some(2),
+            Operator::Amp => Some(3),
+            Operator::Eq | Operator::Ne => Some(4),
+            Operator::Lt | Operator::Le | Operator::Gt | Operator::Ge => Some(5),
+            Operator::Shl | Operator::Shr => Some(6),
+            Operator::Plus | Operator::Minus => Some(7),
+            Operator::Star | Operator::Slash | Operator::Percent => Some(8),
+            Operator::Tilde => None,
+        }
+    }
+
+    /// Whether the operator can stand before a single operand
+    pub fn is_unary(&self) -> bool {
+        matches!(*self, Operator::Plus | Operator::Minus | Operator::Tilde)
+    }
+}
+
+impl fmt::Display for Operator {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let symbol = match *self {
+            Operator::Plus => "+",
+            Operator::Minus => "-",
+            Operator::Star => "*",
+            Operator::Slash => "/",
+            Operator::Percent => "%",
+            Operator::Amp => "&",
+            Operator::Pipe => "|",
+            Operator::Caret => "^",
+            Operator::Tilde => "~",
+            Operator::Shl => "<<",
+            Operator::Shr => ">>",
+            Operator::Eq => "==",
+            Operator::Ne => "!=",
+            Operator::Lt => "<",
+            Operator::Le => "<=",
+            Operator::Gt => ">",
+            Operator::Ge => ">=",
+        };
+        write!(f, "{}", symbol)
+    }
+}
+
+/// Selects one byte of a word
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Function {
+    /// `lo(x)`, the low byte of `x`
+    Lo,
+    /// `hi(x)`, the high byte of `x`
+    Hi,
+}
+
+impl Function {
+    pub fn from_name(name: &str) -> Option<Function> {
+        if name.eq_ignore_ascii_case("lo") {
+            Some(Function::Lo)
+        } else if name.eq_ignore_ascii_case("hi") {
+            Some(Function::Hi)
+        } else {
+            None
+        }
+    }
+}
+
+impl fmt::Display for Function {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Function::Lo => write!(f, "lo"),
+            Function::Hi => write!(f, "hi"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ExprKind {
+    Number(i64),
+    /// A reference to a label or constant
+    Symbol(String),
+    Unary(Operator, Box<Expr>),
+    Binary(Operator, Box<Expr>, Box<Expr>),
+    Call(Function, Box<Expr>),
+}
+
+/// A constant expression along with its location in the source
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Expr {
+    pub kind: ExprKind,
+    pub span: Span,
+}
+
+impl Expr {
+    pub fn new(kind: ExprKind, span: Span) -> Expr {
+        Expr { kind, span }
+    }
+
+    /// Computes the value of the expression. The value of a symbol is
+    /// looked up with `lookup`, which is given its name and location.
+    pub fn eval<F>(&self, lookup: &mut F) -> Result<i64, AssembleError>
+        where F: FnMut(&str, Span) -> Result<i64, AssembleError>
+    {
+        let overflow = || AssembleError::Overflow { span: self.span };
+        match self.kind {
+            ExprKind::Number(n) => Ok(n),
+            ExprKind::Symbol(ref name) => lookup(name, self.span),
+            ExprKind::Unary(op, ref operand) => {
+                let value = operand.eval(lookup)?;
+                match op {
+                    Operator::Minus => value.checked_neg().ok_or_else(overflow),
+                    Operator::Tilde => Ok(!value),
+                    _ => Ok(value),
+                }
+            },
+            ExprKind::Binary(op, ref lhs, ref rhs) => {
+                let (a, b) = (lhs.eval(lookup)?, rhs.eval(lookup)?);
+                if b == 0 && matches!(op, Operator::Slash | Operator::Percent) {
+                    return Err(AssembleError::DivisionByZero { span: rhs.span });
+                }
+                let value = match op {
+                    Operator::Plus => a.checked_add(b),
+                    Operator::Minus => a.checked_sub(b),
+                    Operator::Star => a.checked_mul(b),
+                    Operator::Slash => a.checked_div(b),
+                    Operator::Percent => a.checked_rem(b),
+                    Operator::Amp => Some(a & b),
+                    Operator::Pipe => Some(a | b),
+                    Operator::Caret => Some(a ^ b),
+                    Operator::Shl => shift(b).and_then(|b| i64::try_from((a as i128) << b).ok()),
+                    Operator::Shr => shift(b).map(|b| a >> b),
+                    // Comparisons give 1 when they hold and 0 otherwise
+                    Operator::Eq => Some((a == b) as i64),
+                    Operator::Ne => Some((a != b) as i64),
+                    Operator::Lt => Some((a < b) as i64),
+                    Operator::Le => Some((a <= b) as i64),
+                    Operator::Gt => Some((a > b) as i64),
+                    Operator::Ge => Some((a >= b) as i64),
+                    Operator::Tilde => None,
+                };
+                value.ok_or_else(overflow)
+            },
+            ExprKind::Call(function, ref arg) => {
+                let value = arg.eval(lookup)?;
+                match function {
+                    Function::Lo => Ok(value & 0xff),
+                    Function::Hi => Ok((value >> 8) & 0xff),
+                }
+            },
+        }
+    }
+
+    /// Calls `f` with every symbol reference in the expression, which it
+    /// may replace
+    pub fn visit_symbols<F>(&mut self, f: &mut F)
+        where F: FnMut(&mut Expr)
+    {
+        match self.kind {
+            ExprKind::Number(_) => {},
+            ExprKind::Symbol(_) => f(self),
+            ExprKind::Unary(_, ref mut operand) | ExprKind::Call(_, ref mut operand) => operand.visit_symbols(f),
+            ExprKind::Binary(_, ref mut lhs, ref mut rhs) => {
+                lhs.visit_symbols(f);
+                rhs.visit_symbols(f);
+            },
+        }
+    }
+}
+
+// Shift amounts past the width of the value are overflows
+fn shift(amount: i64) -> Option<u32> {
+    if (0..64).contains(&amount) { Some(amount as u32) } else { None }
+}
+
+impl fmt::Display for Expr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.kind {
+            ExprKind::Number(n) => write!(f, "{}", n),
+            ExprKind::Symbol(ref name) => write!(f, "{}", name),
+            ExprKind::Unary(op, ref operand) => write!(f, "{}{}", op, operand),
+            ExprKind::Binary(op, ref lhs, ref rhs) => write!(f, "({} {} {})", lhs, op, rhs),
+            ExprKind::Call(function, ref arg) => write!(f, "{}({})", function, arg),
+        }
+    }
+}
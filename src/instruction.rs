@@ -0,0 +1,234 @@
+use parser::Register;
+
+/// Size of an immediate operand within an instruction
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Width {
+    /// 4 bits, the `n` of `DRW Vx, Vy, n`
+    Nibble,
+    /// 8 bits, the `kk` of `LD Vx, kk`
+    Byte,
+    /// 12 bits, the `nnn` of `JP nnn`
+    Address,
+    /// 16 bits, a value emitted by `DW`
+    Word,
+}
+
+impl Width {
+    /// Largest value an operand of this width can hold
+    pub fn max(&self) -> u16 {
+        match *self {
+            Width::Nibble => 0x000f,
+            Width::Byte => 0x00ff,
+            Width::Address => 0x0fff,
+            Width::Word => 0xffff,
+        }
+    }
+
+    /// Smallest value an operand of this width can hold. Negative values
+    /// are stored in two's complement, except for addresses, which cannot
+    /// be negative.
+    pub fn min(&self) -> i64 {
+        match *self {
+            Width::Address => 0,
+            _ => -(self.max() as i64 + 1) / 2,
+        }
+    }
+}
+
+/// A Chip-8 instruction as listed in Cowgod's technical reference. The type
+/// of immediate operands is `T`, so the same instruction can hold operands
+/// as written in the source or as resolved numbers ready to be encoded.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Instruction<T> {
+    /// 00E0
+    Cls,
+    /// 00EE
+    Ret,
+    /// 0nnn
+    Sys { addr: T },
+    /// 1nnn
+    Jp { addr: T },
+    /// 2nnn
+    Call { addr: T },
+    /// 3xkk
+    SeByte { x: Register, byte: T },
+    /// 4xkk
+    SneByte { x: Register, byte: T },
+    /// 5xy0
+    SeReg { x: Register, y: Register },
+    /// 6xkk
+    LdByte { x: Register, byte: T },
+    /// 7xkk
+    AddByte { x: Register, byte: T },
+    /// 8xy0
+    LdReg { x: Register, y: Register },
+    /// 8xy1
+    Or { x: Register, y: Register },
+    /// 8xy2
+    And { x: Register, y: Register },
+    /// 8xy3
+    Xor { x: Register, y: Register },
+    /// 8xy4
+    AddReg { x: Register, y: Register },
+    /// 8xy5
+    Sub { x: Register, y: Register },
+    /// 8xy6
+    Shr { x: Register, y: Register },
+    /// 8xy7
+    Subn { x: Register, y: Register },
+    /// 8xyE
+    Shl { x: Register, y: Register },
+    /// 9xy0
+    SneReg { x: Register, y: Register },
+    /// Annn
+    LdI { addr: T },
+    /// Bnnn
+    JpV0 { addr: T },
+    /// Cxkk
+    Rnd { x: Register, byte: T },
+    /// Dxyn
+    Drw { x: Register, y: Register, n: T },
+    /// Ex9E
+    Skp { x: Register },
+    /// ExA1
+    Sknp { x: Register },
+    /// Fx07
+    LdVxDt { x: Register },
+    /// Fx0A
+    LdVxK { x: Register },
+    /// Fx15
+    LdDtVx { x: Register },
+    /// Fx18
+    LdStVx { x: Register },
+    /// Fx1E
+    AddIVx { x: Register },
+    /// Fx29
+    LdFVx { x: Register },
+    /// Fx33
+    LdBVx { x: Register },
+    /// Fx55
+    LdIValVx { x: Register },
+    /// Fx65
+    LdVxIVal { x: Register },
+}
+
+impl<T> Instruction<T> {
+    /// Converts every immediate operand with `f`, which is also given the
+    /// width the operand has in the encoded instruction
+    pub fn map<U, E, F>(self, mut f: F) -> Result<Instruction<U>, E>
+        where F: FnMut(T, Width) -> Result<U, E>
+    {
+        Ok(match self {
+            Instruction::Cls => Instruction::Cls,
+            Instruction::Ret => Instruction::Ret,
+            Instruction::Sys { addr } => Instruction::Sys { addr: f(addr, Width::Address)? },
+            Instruction::Jp { addr } => Instruction::Jp { addr: f(addr, Width::Address)? },
+            Instruction::Call { addr } => Instruction::Call { addr: f(addr, Width::Address)? },
+            Instruction::SeByte { x, byte } => Instruction::SeByte { x, byte: f(byte, Width::Byte)? },
+            Instruction::SneByte { x, byte } => Instruction::SneByte { x, byte: f(byte, Width::Byte)? },
+            Instruction::SeReg { x, y } => Instruction::SeReg { x, y },
+            Instruction::LdByte { x, byte } => Instruction::LdByte { x, byte: f(byte, Width::Byte)? },
+            Instruction::AddByte { x, byte } => Instruction::AddByte { x, byte: f(byte, Width::Byte)? },
+            Instruction::LdReg { x, y } => Instruction::LdReg { x, y },
+            Instruction::Or { x, y } => Instruction::Or { x, y },
+            Instruction::And { x, y } => Instruction::And { x, y },
+            Instruction::Xor { x, y } => Instruction::Xor { x, y },
+            Instruction::AddReg { x, y } => Instruction::AddReg { x, y },
+            Instruction::Sub { x, y } => Instruction::Sub { x, y },
+            Instruction::Shr { x, y } => Instruction::Shr { x, y },
+            Instruction::Subn { x, y } => Instruction::Subn { x, y },
+            Instruction::Shl { x, y } => Instruction::Shl { x, y },
+            Instruction::SneReg { x, y } => Instruction::SneReg { x, y },
+            Instruction::LdI { addr } => Instruction::LdI { addr: f(addr, Width::Address)? },
+            Instruction::JpV0 { addr } => Instruction::JpV0 { addr: f(addr, Width::Address)? },
+            Instruction::Rnd { x, byte } => Instruction::Rnd { x, byte: f(byte, Width::Byte)? },
+            Instruction::Drw { x, y, n } => Instruction::Drw { x, y, n: f(n, Width::Nibble)? },
+            Instruction::Skp { x } => Instruction::Skp { x },
+            Instruction::Sknp { x } => Instruction::Sknp { x },
+            Instruction::LdVxDt { x } => Instruction::LdVxDt { x },
+            Instruction::LdVxK { x } => Instruction::LdVxK { x },
+            Instruction::LdDtVx { x } => Instruction::LdDtVx { x },
+            Instruction::LdStVx { x } => Instruction::LdStVx { x },
+            Instruction::AddIVx { x } => Instruction::AddIVx { x },
+            Instruction::LdFVx { x } => Instruction::LdFVx { x },
+            Instruction::LdBVx { x } => Instruction::LdBVx { x },
+            Instruction::LdIValVx { x } => Instruction::LdIValVx { x },
+            Instruction::LdVxIVal { x } => Instruction::LdVxIVal { x },
+        })
+    }
+}
+
+impl Instruction<u16> {
+    /// Returns the opcode of the instruction. Operands are truncated to
+    /// their width.
+    pub fn encode(&self) -> u16 {
+        match *self {
+            Instruction::Cls => 0x00e0,
+            Instruction::Ret => 0x00ee,
+            Instruction::Sys { addr } => nnn(0x0, addr),
+            Instruction::Jp { addr } => nnn(0x1, addr),
+            Instruction::Call { addr } => nnn(0x2, addr),
+            Instruction::SeByte { x, byte } => xkk(0x3, x, byte),
+            Instruction::SneByte { x, byte } => xkk(0x4, x, byte),
+            Instruction::SeReg { x, y } => xyn(0x5, x, y, 0x0),
+            Instruction::LdByte { x, byte } => xkk(0x6, x, byte),
+            Instruction::AddByte { x, byte } => xkk(0x7, x, byte),
+            Instruction::LdReg { x, y } => xyn(0x8, x, y, 0x0),
+            Instruction::Or { x, y } => xyn(0x8, x, y, 0x1),
+            Instruction::And { x, y } => xyn(0x8, x, y, 0x2),
+            Instruction::Xor { x, y } => xyn(0x8, x, y, 0x3),
+            Instruction::AddReg { x, y } => xyn(0x8, x, y, 0x4),
+            Instruction::Sub { x, y } => xyn(0x8, x, y, 0x5),
+            Instruction::Shr { x, y } => xyn(0x8, x, y, 0x6),
+            Instruction::Subn { x, y } => xyn(0x8, x, y, 0x7),
+            Instruction::Shl { x, y } => xyn(0x8, x, y, 0xe),
+            Instruction::SneReg { x, y } => xyn(0x9, x, y, 0x0),
+            Instruction::LdI { addr } => nnn(0xa, addr),
+            Instruction::JpV0 { addr } => nnn(0xb, addr),
+            Instruction::Rnd { x, byte } => xkk(0xc, x, byte),
+            Instruction::Drw { x, y, n } => xyn(0xd, x, y, n),
+            Instruction::Skp { x } => xkk(0xe, x, 0x9e),
+            Instruction::Sknp { x } => xkk(0xe, x, 0xa1),
+            Instruction::LdVxDt { x } => xkk(0xf, x, 0x07),
+            Instruction::LdVxK { x } => xkk(0xf, x, 0x0a),
+            Instruction::LdDtVx { x } => xkk(0xf, x, 0x15),
+            Instruction::LdStVx { x } => xkk(0xf, x, 0x18),
+            Instruction::AddIVx { x } => xkk(0xf, x, 0x1e),
+            Instruction::LdFVx { x } => xkk(0xf, x, 0x29),
+            Instruction::LdBVx { x } => xkk(0xf, x, 0x33),
+            Instruction::LdIValVx { x } => xkk(0xf, x, 0x55),
+            Instruction::LdVxIVal { x } => xkk(0xf, x, 0x65),
+        }
+    }
+}
+
+fn nnn(op: u16, addr: u16) -> u16 {
+    (op << 12) | (addr & Width::Address.max())
+}
+
+fn xkk(op: u16, x: Register, byte: u16) -> u16 {
+    (op << 12) | ((x.number() as u16) << 8) | (byte & Width::Byte.max())
+}
+
+fn xyn(op: u16, x: Register, y: Register, n: u16) -> u16 {
+    (op << 12) | ((x.number() as u16) << 8) | ((y.number() as u16) << 4) | (n & Width::Nibble.max())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encodes_instructions() {
+        assert_eq!(Instruction::Drw { x: Register::V1, y: Register::V2, n: 5 }.encode(), 0xd125);
+        assert_eq!(Instruction::LdByte { x: Register::Va, byte: 0x42 }.encode(), 0x6a42);
+        assert_eq!(Instruction::<u16>::LdBVx { x: Register::V7 }.encode(), 0xf733);
+        // Operands are truncated to their width
+        assert_eq!(Instruction::Jp { addr: 0xf234 }.encode(), 0x1234);
+
+        let widths = Instruction::Drw { x: Register::V0, y: Register::V1, n: -1i64 }
+            .map(|n, width| if n >= width.min() { Ok((n & width.max() as i64) as u16) } else { Err(width) });
+        assert_eq!(widths.map(|instruction| instruction.encode()), Ok(0xd01f));
+        assert_eq!(Instruction::Call { addr: -1i64 }.map(|_, width| Err::<u16, _>(width)), Err(Width::Address));
+    }
+}
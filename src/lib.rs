@@ -1 +1,49 @@
+pub mod bitmap;
+pub mod diagnostic;
+pub mod error;
+pub mod expr;
+pub mod instruction;
+pub mod listing;
 pub mod parser;
+
+use std::io::Read;
+
+use error::Errors;
+use parser::{Files, Options, Stream};
+
+/// The code of an assembled program along with the warnings raised while
+/// assembling it
+#[derive(Debug)]
+pub struct Program {
+    pub code: Vec<u8>,
+    pub warnings: Errors,
+}
+
+/// Assembles the source read from `input`, such as a file or standard
+/// input. Included files are searched from the current directory.
+pub fn assemble<R: Read>(input: R, options: &Options) -> Result<Program, Errors> {
+    let mut warnings = Errors::new();
+    let assembly = parser::assemble(&mut Stream::new(input), options, &mut Files::new(), &mut warnings)?;
+    Ok(Program { code: assembly.code, warnings })
+}
+
+/// Assembles source code held in a string with the default options
+pub fn assemble_str(source: &str) -> Result<Program, Errors> {
+    assemble(source.as_bytes(), &Options::default())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_any_input() {
+        let program = assemble(&b"CLS\n"[..], &Options::default()).unwrap();
+        assert_eq!(program.code, vec![0x00, 0xe0]);
+        let options = Options { truncate: true, ..Options::default() };
+        let program = assemble("LD V0, 300".as_bytes(), &options).unwrap();
+        assert_eq!(program.code, vec![0x60, 0x2c]);
+        assert_eq!(program.warnings.len(), 1);
+        assert!(assemble_str("JP nowhere").is_err());
+    }
+}
@@ -0,0 +1,164 @@
+use std::fmt::Write;
+
+use diagnostic::source_lines;
+use parser::{Assembly, Files, Span};
+
+// Bytes shown on a line of the listing, the rest of a statement's bytes
+// going on the lines after it
+const BYTES_PER_LINE: usize = 4;
+
+/// Formats an assembler listing: every line of the input with its number,
+/// the address of its first statement and the bytes it emits, followed by
+/// the lines that macros, repetitions and included files used on it expand
+/// to, marked with a `+` for every level of nesting. A table of the labels
+/// and constants of the program comes last. `sources` holds the contents of
+/// every file in `files`.
+///
+/// ```text
+///     1  0200  60 05        start:  LD V0, 5
+///     2                             wait 3
+///        0202  61 03        +    LD V1, n
+/// ```
+pub fn listing(assembly: &Assembly, files: &Files, sources: &[&str]) -> String {
+    let mut out = String::new();
+    let mut lines: Vec<&str> = source_lines(sources.first().cloned().unwrap_or("")).collect();
+    if lines.last() == Some(&"") {
+        lines.pop();
+    }
+
+    // Every row along with the line of the input it comes from and how
+    // deeply it is expanded
+    let mut rows = rows(assembly).into_iter().map(|row| {
+        let (line, depth) = origin(row.span, files);
+        (row, line, depth)
+    }).peekable();
+    for (i, &text) in lines.iter().enumerate() {
+        let number = i as u32 + 1;
+        let mut listed = false;
+        while let Some((row, _, depth)) = rows.next_if(|&(_, line, _)| line <= number) {
+            let bytes = &row.bytes[..];
+            if depth == 0 && !listed {
+                write_line(&mut out, Some(number), Some(row.address), bytes, text);
+            } else if depth == 0 {
+                write_line(&mut out, None, Some(row.address), bytes, "");
+            } else {
+                if !listed {
+                    write_line(&mut out, Some(number), None, &[], text);
+                }
+                let source = sources.get(row.span.file).cloned().unwrap_or("");
+                let line = source_lines(source).nth(row.span.line as usize - 1).unwrap_or("");
+                write_line(&mut out, None, Some(row.address), bytes, &("+".repeat(depth) + line));
+            }
+            listed = true;
+        }
+        if !listed {
+            write_line(&mut out, Some(number), None, &[], text);
+        }
+    }
+
+    if !assembly.symbols.is_empty() {
+        let width = assembly.symbols.iter().map(|(name, _)| name.len()).max().unwrap_or(0);
+        let _ = writeln!(out, "\nSymbols:");
+        for (name, value) in &assembly.symbols {
+            let _ = match *value {
+                0.. => writeln!(out, "    {:<width$}  {:#06x}", name, value, width = width),
+                _ => writeln!(out, "    {:<width$}  {}", name, value, width = width),
+            };
+        }
+    }
+    out
+}
+
+// The statements of a line, or of a line of a macro, repetition or included
+// file each time it is expanded
+struct Row {
+    address: u16,
+    bytes: Vec<u8>,
+    span: Span,
+}
+
+// Groups consecutive statements that come from the same line
+fn rows(assembly: &Assembly) -> Vec<Row> {
+    let mut rows: Vec<Row> = vec![];
+    for emitted in &assembly.map {
+        let bytes = &assembly.code[emitted.bytes.clone()];
+        match rows.last_mut() {
+            Some(row) if same_line(row.span, emitted.span) => {
+                // The address of a line is that of its first byte
+                if row.bytes.is_empty() {
+                    row.address = emitted.address;
+                }
+                row.bytes.extend_from_slice(bytes);
+            },
+            _ => rows.push(Row { address: emitted.address, bytes: bytes.to_vec(), span: emitted.span }),
+        }
+    }
+    rows
+}
+
+fn same_line(a: Span, b: Span) -> bool {
+    a.file == b.file && a.line == b.line && a.expansion == b.expansion
+}
+
+// The line of the input a span comes from, going up through the macros,
+// repetitions and files it was expanded from, and how many levels of them
+// there are
+fn origin(mut span: Span, files: &Files) -> (u32, usize) {
+    let mut depth = 0;
+    loop {
+        if let Some(call) = span.expansion.and_then(|i| files.expansion(i)) {
+            span = call.call;
+        } else if let Some(include) = files.included_from(span.file) {
+            span = include;
+        } else {
+            return (span.line, depth);
+        }
+        depth += 1;
+    }
+}
+
+// Writes a line of the listing, continued on the lines after it if there
+// are more bytes than fit in one
+fn write_line(out: &mut String, number: Option<u32>, address: Option<u16>, bytes: &[u8], text: &str) {
+    let mut chunks = bytes.chunks(BYTES_PER_LINE);
+    let mut address = address;
+    let mut number = number.map_or(String::new(), |n| n.to_string());
+    let mut text = text;
+    loop {
+        let chunk = chunks.next().unwrap_or(&[]);
+        let hex: Vec<String> = chunk.iter().map(|b| format!("{:02X}", b)).collect();
+        let location = address.map_or(String::new(), |a| format!("{:04X}", a));
+        let line = format!("{:>5}  {:4}  {:<11}  {}", number, location, hex.join(" "), text);
+        let _ = writeln!(out, "{}", line.trim_end());
+
+        if chunks.len() == 0 {
+            return;
+        }
+        address = address.map(|a| a.wrapping_add(chunk.len() as u16));
+        number.clear();
+        text = "";
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use error::Errors;
+    use parser::{assemble, Options, Stream};
+
+    use super::*;
+
+    #[test]
+    fn lists_source() {
+        let source = "MACRO twice x\n  DB x, x\nENDM\nN EQU 9\n\nstart: CLS ; clear\ntwice N\nDB 1, 2, 3, 4, 5\n";
+        let (mut stream, mut files) = (Stream::from(source), Files::new());
+        let assembly = assemble(&mut stream, &Options::default(), &mut files, &mut Errors::new()).unwrap();
+        let listing = listing(&assembly, &files, &[source]);
+        let lines: Vec<&str> = listing.lines().collect();
+        assert_eq!(lines[3], "    4                     N EQU 9");
+        assert_eq!(lines[4], "    5");
+        assert_eq!(lines[5], "    6  0200  00 E0        start: CLS ; clear");
+        assert_eq!(lines[7], "       0202  09 09        +  DB x, x");
+        assert_eq!(lines[8..10], ["    8  0204  01 02 03 04  DB 1, 2, 3, 4, 5", "       0208  05"]);
+        assert_eq!(lines[10..], ["", "Symbols:", "    N      0x0009", "    start  0x0200"]);
+    }
+}
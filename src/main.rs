@@ -2,14 +2,18 @@
 extern crate clap;
 extern crate c8asm;
 
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use std::fs;
 use std::fs::File;
-use std::io::Read;
+use std::io;
 use std::io::Write;
 
 use clap::{Arg, App};
 
-use c8asm::parser::Stream;
+use c8asm::diagnostic::Renderer;
+use c8asm::error::{AssembleError, Errors};
+use c8asm::listing::listing;
+use c8asm::parser::{assemble, parse_literal, Assembly, Files, Options, Stream, LOAD_ADDRESS};
 
 fn main() {
     let matches = App::new("c8asm")
@@ -28,38 +32,161 @@ fn main() {
             .value_name("FILE")
             .help("File name of the assembled output")
             .required(true))
+        .arg(Arg::with_name("listing")
+            .short("l")
+            .long("listing")
+            .value_name("FILE")
+            .help("Writes a listing of the source with the address and bytes of every line"))
+        .arg(Arg::with_name("no-color")
+            .long("no-color")
+            .help("Disables colored error messages"))
+        .arg(Arg::with_name("truncate")
+            .long("truncate")
+            .help("Masks operands that are out of range with a warning instead of failing"))
+        .arg(Arg::with_name("base")
+            .short("b")
+            .long("base")
+            .value_name("ADDR")
+            .help("Address the program is loaded at, 0x200 by default and 0x600 for ETI 660 programs")
+            .validator(|s| parse_address(&s).map(|_| ())))
+        .arg(Arg::with_name("define")
+            .short("D")
+            .value_name("NAME=VALUE")
+            .help("Defines a constant, whose value is 1 if not given")
+            .multiple(true)
+            .number_of_values(1)
+            .validator(|s| parse_define(&s).map(|_| ())))
+        .arg(Arg::with_name("include")
+            .short("I")
+            .value_name("DIR")
+            .help("Adds a directory to search for included files")
+            .multiple(true)
+            .number_of_values(1))
         .get_matches();
 
     let input_file_path = Path::new(matches.value_of("input").unwrap());
     let output_file_path = Path::new(matches.value_of("output").unwrap());
+    let options = Options {
+        truncate: matches.is_present("truncate"),
+        base: matches.value_of("base").map_or(LOAD_ADDRESS, |s| parse_address(s).unwrap()),
+        defines: matches.values_of("define")
+            .map_or(vec![], |values| merge_defines(values.map(|s| parse_define(s).unwrap()))),
+        include_dirs: matches.values_of("include").map_or(vec![], |values| values.map(PathBuf::from).collect()),
+    };
 
-    let input_file = File::open(input_file_path).unwrap();
-
-    let mut stream = Stream::new(input_file.bytes());
-    let mut tokens = vec![];
+    let mut files = Files::new();
+    files.add(input_file_path);
+    let mut warnings = Errors::new();
+    let result = assemble_file(input_file_path, output_file_path, &options, &mut files, &mut warnings);
 
-    loop {
-        let maybe_token = stream.next_token();
-        if maybe_token.is_none() {
-            break;
+    let renderer = Renderer::new(!matches.is_present("no-color"));
+    // Name and contents of every file, for showing the line of a diagnostic
+    let sources: Vec<(String, String)> = files.iter().map(|path| {
+        let source = fs::read(path)
+            .map(|bytes| String::from_utf8_lossy(&bytes).into_owned())
+            .unwrap_or_default();
+        (path.display().to_string(), source)
+    }).collect();
+    let source_of = |file: usize| {
+        let (ref name, ref source) = sources[file];
+        (name.as_str(), source.as_str())
+    };
+    // Points at the macro uses an error comes from, innermost first
+    let notes = |err: &AssembleError| {
+        let mut out = String::new();
+        let mut expansion = err.span().and_then(|span| span.expansion);
+        while let Some(macro_use) = expansion.and_then(|i| files.expansion(i)) {
+            let (name, source) = source_of(macro_use.call.file);
+            let message = format!("in this expansion of `{}`", macro_use.name);
+            out.push_str(&renderer.render_note(&message, macro_use.call, name, source));
+            expansion = macro_use.call.expansion;
         }
-        let token = maybe_token.unwrap();
-        tokens.push(token);
+        out
+    };
+    for warning in &warnings {
+        let (name, source) = source_of(warning.span().map_or(0, |span| span.file));
+        eprint!("{}", renderer.render_warning(warning, name, source));
+        eprintln!("{}", notes(warning));
     }
 
-    let code = self::c8asm::parser::code_gen(&tokens);
-    match code {
-        Ok(c) => {
-            let mut output_file = File::create(output_file_path).unwrap();
-            for i in c {
-                let buf = [((i & 0xff00) >> 8) as u8, (i & 0x00ff) as u8];
-                output_file.write(&buf);
-            }
-            std::process::exit(0);
+    // Written once the sources are read, as it shows them
+    let listed = match (result, matches.value_of("listing")) {
+        (Ok(ref assembly), Some(path)) => {
+            let contents: Vec<&str> = sources.iter().map(|(_, source)| source.as_str()).collect();
+            fs::write(path, listing(assembly, &files, &contents))
+                .map_err(|e| Errors::from(io_error(Path::new(path), e)))
         },
-        Err(t) => {
-            println!("error: unexpected token: {:?}", t);
+        (result, _) => result.map(|_| ()),
+    };
+
+    match listed {
+        Ok(()) => std::process::exit(0),
+        Err(errors) => {
+            for err in &errors {
+                let (name, source) = source_of(err.span().map_or(0, |span| span.file));
+                eprint!("{}", renderer.render(err, name, source));
+                eprintln!("{}", notes(err));
+            }
+            match errors.len() {
+                1 => eprintln!("aborting due to previous error"),
+                n => eprintln!("aborting due to {} previous errors", n),
+            }
             std::process::exit(1);
         }
     }
 }
+
+fn assemble_file(input_file_path: &Path, output_file_path: &Path, options: &Options,
+                 files: &mut Files, warnings: &mut Errors) -> Result<Assembly, Errors> {
+    let input_file = File::open(input_file_path).map_err(|e| io_error(input_file_path, e))?;
+    let mut stream = Stream::new(input_file);
+
+    let assembly = assemble(&mut stream, options, files, warnings)?;
+    let mut output_file = File::create(output_file_path).map_err(|e| io_error(output_file_path, e))?;
+    output_file.write_all(&assembly.code).map_err(|e| io_error(output_file_path, e))?;
+    Ok(assembly)
+}
+
+// Parses a number written as in the source, optionally negative
+fn parse_number(s: &str) -> Result<i64, String> {
+    let parsed = match s.strip_prefix('-') {
+        Some(rest) => parse_literal(rest).map(|n| -n),
+        None => parse_literal(s),
+    };
+    parsed.ok_or_else(|| format!("invalid number `{}`", s))
+}
+
+fn parse_address(s: &str) -> Result<u16, String> {
+    let n = parse_number(s)?;
+    if !(0..=0xffff).contains(&n) {
+        return Err(format!("address `{}` is out of range", s));
+    }
+    Ok(n as u16)
+}
+
+// Parses the `NAME=VALUE` or `NAME` argument of -D
+fn parse_define(s: &str) -> Result<(String, i64), String> {
+    let (name, value) = match s.find('=') {
+        Some(i) => (&s[..i], parse_number(&s[i + 1..])?),
+        None => (s, 1),
+    };
+    if name.is_empty() {
+        return Err(format!("missing constant name in `{}`", s));
+    }
+    Ok((name.to_owned(), value))
+}
+
+// Keeps the last value of a constant defined more than once with -D
+fn merge_defines<I: Iterator<Item = (String, i64)>>(defines: I) -> Vec<(String, i64)> {
+    let mut merged: Vec<(String, i64)> = vec![];
+    for (name, value) in defines {
+        merged.retain(|(other, _)| *other != name);
+        merged.push((name, value));
+    }
+    merged
+}
+
+// Names the file an I/O error occurred on
+fn io_error(path: &Path, err: io::Error) -> AssembleError {
+    AssembleError::Io(io::Error::new(err.kind(), format!("{}: {}", path.display(), err)))
+}
@@ -1,23 +1,136 @@
-use std::fs::File;
-use std::io::Bytes;
+use std::cell::Cell;
+use std::collections::HashMap;
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, Read};
+use std::iter;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::str;
+
+use bitmap::{self, Bitmap, BitmapError, LARGE_SPRITE_SIZE};
+use error::{AssembleError, Errors, LexError};
+use expr::{Expr, ExprKind, Function, Operator};
+use instruction::{Instruction, Width};
 
 
 const COMMENT_CHAR: char = ';';
 const NEWLINE_CHAR: char = '\n';
 const COMMA_CHAR: char = ',';
-const SPACE_CHAR: char = ' ';
-const TAB_CHAR: char = '\t';
+const RETURN_CHAR: char = '\r';
+const BOM_CHAR: char = '\u{feff}';
+const LABEL_CHAR: char = ':';
+const QUOTE_CHAR: char = '\'';
+const STRING_CHAR: char = '"';
+const PIXEL_OFF: char = '.';
+const PIXEL_ON: char = 'X';
+// Longest mnemonic, directive or register name
+const MAX_KEYWORD_LEN: usize = 16;
 
-#[derive(Debug, Copy, Clone)]
+/// Address at which Chip-8 programs are loaded by the interpreter
+pub const LOAD_ADDRESS: u16 = 0x200;
+/// Address at which ETI 660 programs are loaded
+pub const ETI_660_LOAD_ADDRESS: u16 = 0x600;
+// Every Chip-8 instruction is two bytes long
+const INSTRUCTION_SIZE: u16 = 2;
+/// Number of macro expansions that may be nested in one another, beyond
+/// which a macro is taken to expand itself endlessly
+pub const MAX_MACRO_DEPTH: usize = 32;
+/// Number of times `REPT` and `FOR` may repeat their block
+pub const MAX_REPETITIONS: i64 = 0x10000;
+/// Number of macro expansions and repetitions a program may have in all
+pub const MAX_EXPANSIONS: usize = 0x40000;
+/// Number of tokens macro expansions and repetitions may produce in all,
+/// far more than a program of 64 KiB is made of
+pub const MAX_EXPANDED_TOKENS: usize = 0x80000;
+/// Number of constants that may be resolved in one another, e.g. `A` in
+/// `A EQU B + 1`, `B EQU 2`
+pub const MAX_SYMBOL_DEPTH: usize = 64;
+
+/// An instruction or a directive
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
 pub enum Mnemonic {
     Add, And, Call, Cls,
     Drw, Jp, Ld, Or,
     Ret, Rnd, Se, Shl,
     Shr, Sknp, Skp, Sne,
     Sub, Subn, Sys, Xor,
+    // Directives
+    Db, Dw, Org, Align,
+    Equ, Const, Sprite,
+    IncSprite, Include, IncBin,
+    Macro, Endm,
+    If, ElseIf, Else, EndIf,
+    IfDef, IfNDef,
+    Rept, For, Endr,
+    Text, TextW, CharMap,
 }
 
-#[derive(Debug, Copy, Clone)]
+impl Mnemonic {
+    /// Describes the operand forms accepted by the mnemonic
+    pub fn operands(&self) -> &'static str {
+        match *self {
+            Mnemonic::Add => "Vx, Vy | Vx, byte | I, Vx",
+            Mnemonic::And | Mnemonic::Or | Mnemonic::Xor |
+            Mnemonic::Sub | Mnemonic::Subn => "Vx, Vy",
+            Mnemonic::Call | Mnemonic::Sys => "addr",
+            Mnemonic::Cls | Mnemonic::Ret => "no operands",
+            Mnemonic::Drw => "Vx, Vy, nibble",
+            Mnemonic::Jp => "addr | V0, addr",
+            Mnemonic::Ld => "Vx, byte | Vx, Vy | I, addr | Vx, DT | Vx, K | DT, Vx | \
+                             ST, Vx | F, Vx | B, Vx | [I], Vx | Vx, [I]",
+            Mnemonic::Rnd => "Vx, byte",
+            Mnemonic::Se | Mnemonic::Sne => "Vx, byte | Vx, Vy",
+            Mnemonic::Shl | Mnemonic::Shr => "Vx {, Vy}",
+            Mnemonic::Skp | Mnemonic::Sknp => "Vx",
+            Mnemonic::Db => "byte {, byte}",
+            Mnemonic::Dw => "word {, word}",
+            Mnemonic::Org => "addr",
+            Mnemonic::Align => "n",
+            Mnemonic::Equ => "value, preceded by the name of the constant",
+            Mnemonic::Const => "NAME = value",
+            Mnemonic::Sprite => "NAME, followed by rows of `.` and `X`",
+            Mnemonic::IncSprite => "\"file\", x, y, width, height",
+            Mnemonic::Include => "\"file\"",
+            Mnemonic::IncBin => "\"file\", with an optional offset and length",
+            Mnemonic::Macro => "NAME, followed by its parameters",
+            Mnemonic::Endm | Mnemonic::Else | Mnemonic::EndIf | Mnemonic::Endr => "no operands",
+            Mnemonic::Rept => "a count",
+            Mnemonic::For => "NAME = first TO last",
+            Mnemonic::If | Mnemonic::ElseIf => "a condition",
+            Mnemonic::IfDef | Mnemonic::IfNDef => "NAME",
+            Mnemonic::Text | Mnemonic::TextW => "\"text\"",
+            Mnemonic::CharMap => "'c', value",
+        }
+    }
+
+    /// The directive closing a block opened by this one
+    pub fn closing(&self) -> Option<Mnemonic> {
+        match *self {
+            Mnemonic::Macro => Some(Mnemonic::Endm),
+            Mnemonic::If | Mnemonic::IfDef | Mnemonic::IfNDef => Some(Mnemonic::EndIf),
+            Mnemonic::Rept | Mnemonic::For => Some(Mnemonic::Endr),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Mnemonic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // Directives that are only written with a dot keep it
+        match *self {
+            Mnemonic::Const => write!(f, ".const"),
+            Mnemonic::Sprite => write!(f, ".sprite"),
+            Mnemonic::Text => write!(f, ".text"),
+            Mnemonic::TextW => write!(f, ".textw"),
+            Mnemonic::CharMap => write!(f, ".charmap"),
+            _ => write!(f, "{}", format!("{:?}", self).to_uppercase()),
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
 pub enum Register {
     V0, V1, V2, V3,
     V4, V5, V6, V7,
@@ -26,7 +139,7 @@ pub enum Register {
 }
 
 impl Register {
-    fn number(&self) -> u8 {
+    pub fn number(&self) -> u8 {
         match *self {
             Register::V0 => {0}, Register::V1 => {1},
             Register::V2 => {2}, Register::V3 => {3},
@@ -40,158 +153,584 @@ impl Register {
     }
 }
 
-#[derive(Debug)]
-pub enum Token {
-    Opcode(Mnemonic, u32),
-    Reg(Register, u32),
-    ImmConst(u16, u32),
-    F(u32), B(u32), K(u32),
-    I(u32), St(u32), Dt(u32),
-    IVal(u32), Comment(u32),
+impl fmt::Display for Register {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "V{:X}", self.number())
+    }
+}
+
+/// Location of a token in the source. `file` is the index of its file in
+/// `Files`. Line and column are 1-based and refer to the first byte of the
+/// token, `start..end` is its byte range. A token produced by a macro is
+/// located in the macro definition, `expansion` being the index in `Files`
+/// of the expansion it comes from.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Span {
+    pub file: usize,
+    pub line: u32,
+    pub column: u32,
+    pub start: usize,
+    pub end: usize,
+    pub expansion: Option<usize>,
+}
+
+impl Span {
+    /// Number of bytes covered by the span
+    pub fn len(&self) -> usize {
+        self.end.saturating_sub(self.start)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Span from the start of `self` to the end of `other`, or `self` if
+    /// `other` lies elsewhere
+    pub fn to(&self, other: Span) -> Span {
+        if (other.file, other.line, other.expansion) != (self.file, self.line, self.expansion) {
+            return *self;
+        }
+        Span { end: other.end.max(self.end), ..*self }
+    }
+}
+
+impl fmt::Display for Span {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}", self.line, self.column)
+    }
+}
+
+/// The kind of a token. The name of a label or identifier and the contents
+/// of a string or sprite row are the text of the token, see `Token::name`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum TokenKind {
+    Opcode(Mnemonic),
+    Reg(Register),
+    ImmConst(i64),
+    F, B, K,
+    I, St, Dt,
+    IVal, Comment,
+    // A label definition, e.g. `loop:`
+    Label,
+    // A reference to a label or constant used as an operand
+    Ident,
+    // A string in double quotes
+    Str,
+    // The `=` of a `.const` definition
+    Assign,
+    Comma,
+    LParen,
+    RParen,
+    Op(Operator),
+    // A row of a sprite, e.g. `..XX..XX`
+    Pixels,
+}
+
+impl fmt::Display for TokenKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            TokenKind::Opcode(ref m) => write!(f, "{}", m),
+            TokenKind::Reg(ref r) => write!(f, "{}", r),
+            TokenKind::ImmConst(c) => write!(f, "{}", c),
+            TokenKind::F => write!(f, "F"),
+            TokenKind::B => write!(f, "B"),
+            TokenKind::K => write!(f, "K"),
+            TokenKind::I => write!(f, "I"),
+            TokenKind::St => write!(f, "ST"),
+            TokenKind::Dt => write!(f, "DT"),
+            TokenKind::IVal => write!(f, "[I]"),
+            TokenKind::Comment => write!(f, "comment"),
+            TokenKind::Label => write!(f, "label"),
+            TokenKind::Ident => write!(f, "identifier"),
+            TokenKind::Str => write!(f, "string"),
+            TokenKind::Assign => write!(f, "="),
+            TokenKind::Comma => write!(f, ","),
+            TokenKind::LParen => write!(f, "("),
+            TokenKind::RParen => write!(f, ")"),
+            TokenKind::Op(op) => write!(f, "{}", op),
+            TokenKind::Pixels => write!(f, "sprite row"),
+        }
+    }
+}
+
+/// A token along with its location and the text it was read from
+#[derive(Debug, Clone)]
+pub struct Token {
+    pub kind: TokenKind,
+    pub span: Span,
+    text: Option<Text>,
+}
+
+// Where the text of a token lies in the source it was read from, shared by
+// every token of the source
+#[derive(Debug, Clone)]
+struct Text {
+    source: Rc<str>,
+    start: usize,
+    end: usize,
+}
+
+impl Token {
+    /// A token that was not read from a source, whose text is empty
+    pub fn new(kind: TokenKind, span: Span) -> Token {
+        Token { kind, span, text: None }
+    }
+
+    /// The text of the token as written in the source. Tokens expanded from
+    /// a macro or repetition keep the text they were copied from.
+    pub fn text(&self) -> &str {
+        self.text.as_ref().map_or("", |text| &text.source[text.start..text.end])
+    }
+
+    /// The name of a label without its colon, the contents of a string
+    /// between its quotes, escapes included, or else the text of the token
+    pub fn name(&self) -> &str {
+        let text = self.text();
+        match self.kind {
+            TokenKind::Label => text.strip_suffix(LABEL_CHAR).unwrap_or(text),
+            TokenKind::Str => text.strip_prefix(STRING_CHAR).and_then(|t| t.strip_suffix(STRING_CHAR)).unwrap_or(text),
+            _ => text,
+        }
+    }
+
+    // A token whose text is made up rather than read from a source, e.g. a
+    // label renamed by an expansion
+    fn with_text(kind: TokenKind, span: Span, text: String) -> Token {
+        let end = text.len();
+        Token { kind, span, text: Some(Text { source: text.into(), start: 0, end }) }
+    }
+}
+
+impl fmt::Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.kind {
+            TokenKind::Label | TokenKind::Ident | TokenKind::Str | TokenKind::Pixels => write!(f, "{}", self.text()),
+            ref kind => write!(f, "{}", kind),
+        }
+    }
 }
 
+/// Reads tokens from source code held in memory or read from a file,
+/// standard input or any other reader. Iterating over it yields every token
+/// of the source, comments included, and an error for every word that is
+/// not one.
 pub struct Stream {
-    input: Bytes<File>,
+    // The whole input, read up front and shared with the tokens, whose text
+    // lies in it
+    source: Rc<str>,
+    // An error reading the input, reported before its first token
+    error: Option<io::Error>,
+    // Offsets of the U+FFFD characters standing for bytes that are not
+    // UTF-8, in order
+    invalid: Vec<usize>,
+    // The first of them read as part of the current token
+    invalid_at: Option<Span>,
+    // Index of the file in `Files`
+    file: usize,
     line: u32,
+    column: u32,
+    offset: usize,
 }
 
 impl Stream {
-    pub fn new(input: Bytes<File>) -> Stream {
+    pub fn new<R: Read>(input: R) -> Stream {
+        Stream::with_file(input, 0)
+    }
+
+    /// A stream over the file with the given index in `Files`
+    pub fn with_file<R: Read>(mut input: R, file: usize) -> Stream {
+        let mut bytes = vec![];
+        let error = input.read_to_end(&mut bytes).err();
+        // Bytes that are not UTF-8 are read as U+FFFD, as diagnostics show
+        // them, and reported where they are
+        let mut source = String::with_capacity(bytes.len());
+        let mut invalid = vec![];
+        for chunk in bytes.utf8_chunks() {
+            source.push_str(chunk.valid());
+            if !chunk.invalid().is_empty() {
+                invalid.push(source.len());
+                source.push(char::REPLACEMENT_CHARACTER);
+            }
+        }
+        let mut stream = Stream::from_source(source.into(), file);
+        stream.error = error;
+        stream.invalid = invalid;
+        stream
+    }
+
+    // A stream over the source, past its byte order mark if it has one
+    fn from_source(source: Rc<str>, file: usize) -> Stream {
+        let offset = if source.starts_with(BOM_CHAR) { BOM_CHAR.len_utf8() } else { 0 };
         Stream {
-            input: input,
+            source,
+            error: None,
+            invalid: vec![],
+            invalid_at: None,
+            file,
             line: 1,
+            column: 1,
+            offset,
+        }
+    }
+
+    // Builds a token whose text lies at `span` in the source
+    fn token(&self, kind: TokenKind, span: Span) -> Token {
+        let text = Text { source: Rc::clone(&self.source), start: span.start, end: span.end };
+        Token { kind, span, text: Some(text) }
+    }
+
+    // The source text at `span`
+    fn slice(&self, span: Span) -> &str {
+        &self.source[span.start..span.end]
+    }
+
+    // Reads a word up to the next separator, punctuation or comment, or
+    // returns `None` at the end of the input
+    fn word(&mut self) -> Option<Result<Token, LexError>> {
+        let mut span = self.here();
+        while let Some(c) = self.peek_char() {
+            if Stream::is_separator(c) || Stream::is_punctuation(c) || c == COMMENT_CHAR {
+                break;
+            }
+            self.next_char();
+        }
+        if self.offset == span.start {
+            return None;
         }
+        span.end = self.offset;
+        Some(self.create_token(span))
     }
 
-    pub fn next_token(&mut self) -> Option<Token> {
-        let mut buffer: Vec<u8> = vec![];
+    // Reads a comment up to the end of its line
+    fn comment(&mut self) -> Token {
+        let mut span = self.here();
+        while let Some(c) = self.peek_char() {
+            if Stream::is_line_end(c) {
+                break;
+            }
+            self.next_char();
+        }
+        span.end = self.offset;
+        self.token(TokenKind::Comment, span)
+    }
 
-        loop {
-            let bs = self.input.next().map_or(None, |bm| match bm {
-                Ok(b) => Some(b),
-                _ => None
-            });
+    // Reads a character literal, which may contain separators and commas
+    fn char_literal(&mut self) -> Result<Token, LexError> {
+        let mut span = self.here();
+        self.quoted(QUOTE_CHAR);
+        span.end = self.offset;
+        let text = self.slice(span);
+        match parse_literal(text) {
+            Some(value) => Ok(self.token(TokenKind::ImmConst(value), span)),
+            None => Err(LexError::InvalidLiteral { text: text.to_owned(), span }),
+        }
+    }
 
-            match bs {
-                Some(b) => {
-                    if Stream::is_separator(b) {
-                        if buffer.len() > 0 {
-                            let tt = Stream::create_token(buffer, self.line);
-                            if (b as char) == NEWLINE_CHAR {
-                                self.line += 1;
-                            }
-                            return tt;
-                        }
-                        if (b as char) == NEWLINE_CHAR {
-                            self.line += 1;
-                        }
-					} else if (b as char) == COMMENT_CHAR {
-						loop {
-							let bs = self.input.next().map_or(None, |bm| match bm {
-								Ok(b) => Some(b),
-								_ => None
-							});
-							match bs {
-								Some(b) => {
-									if (b as char) == NEWLINE_CHAR {
-										self.line += 1;
-										return Some(Token::Comment(self.line - 1));
-									}
-								},
-								None => { return Some(Token::Comment(self.line)); }
-							}
-						}
-                    } else {
-                        buffer.push(b);
+    // Moves past text in the given quotes, stopping at the end of the line
+    // if they are not closed. Returns whether they are.
+    fn quoted(&mut self, quote: char) -> bool {
+        let start = self.offset;
+        let mut escaped = false;
+        while let Some(c) = self.peek_char() {
+            if Stream::is_line_end(c) {
+                break;
+            }
+            self.next_char();
+            if c == quote && !escaped && self.offset - start > 1 {
+                return true;
+            }
+            escaped = !escaped && c == '\\';
+        }
+        false
+    }
+
+    // Reads a string in double quotes, which may contain the same escapes
+    // as a character literal
+    fn string_literal(&mut self) -> Result<Token, LexError> {
+        let mut span = self.here();
+        let closed = self.quoted(STRING_CHAR);
+        span.end = self.offset;
+        let text = self.slice(span);
+        let inner = &text[1..text.len() - closed as usize];
+        let mut chars = inner.chars();
+        let mut valid = closed;
+        while let Some(c) = chars.next() {
+            if c == '\\' {
+                valid &= chars.next().and_then(unescape).is_some();
+            }
+        }
+        if !valid {
+            return Err(LexError::InvalidLiteral { text: text.to_owned(), span });
+        }
+        Ok(self.token(TokenKind::Str, span))
+    }
+
+    // Reads an operator, a parenthesis, a comma, an `=` or an anonymous
+    // label
+    fn punctuation(&mut self) -> Result<Token, LexError> {
+        let mut span = self.here();
+        let c = self.next_char().unwrap_or_default();
+        let kind = match c {
+            COMMA_CHAR => TokenKind::Comma,
+            '(' => TokenKind::LParen,
+            ')' => TokenKind::RParen,
+            // `+:` and `-:` define anonymous labels
+            '+' | '-' if self.peek_char() == Some(LABEL_CHAR) => {
+                self.next_char();
+                TokenKind::Label
+            },
+            '+' => TokenKind::Op(Operator::Plus),
+            '-' => TokenKind::Op(Operator::Minus),
+            '*' => TokenKind::Op(Operator::Star),
+            '/' => TokenKind::Op(Operator::Slash),
+            '%' => TokenKind::Op(Operator::Percent),
+            '&' => TokenKind::Op(Operator::Amp),
+            '|' => TokenKind::Op(Operator::Pipe),
+            '^' => TokenKind::Op(Operator::Caret),
+            '~' => TokenKind::Op(Operator::Tilde),
+            // Operators of two characters, `=`, `<` and `>`
+            '<' | '>' | '=' | '!' => {
+                let pair = match (c, self.peek_char()) {
+                    ('<', Some('<')) => Some(Operator::Shl),
+                    ('>', Some('>')) => Some(Operator::Shr),
+                    ('<', Some('=')) => Some(Operator::Le),
+                    ('>', Some('=')) => Some(Operator::Ge),
+                    ('=', Some('=')) => Some(Operator::Eq),
+                    ('!', Some('=')) => Some(Operator::Ne),
+                    _ => None,
+                };
+                match (pair, c) {
+                    (Some(op), _) => {
+                        self.next_char();
+                        TokenKind::Op(op)
+                    },
+                    (None, '<') => TokenKind::Op(Operator::Lt),
+                    (None, '>') => TokenKind::Op(Operator::Gt),
+                    (None, '=') => TokenKind::Assign,
+                    _ => {
+                        span.end = self.offset;
+                        return Err(LexError::InvalidToken { text: c.to_string(), span });
                     }
-                },
-                None => {
-                    return Stream::create_token(buffer, self.line);
                 }
+            },
+            _ => {
+                span.end = self.offset;
+                return Err(LexError::InvalidToken { text: c.to_string(), span });
             }
-        }
+        };
+        span.end = self.offset;
+        Ok(self.token(kind, span))
+    }
+
+    fn peek_char(&self) -> Option<char> {
+        self.source[self.offset..].chars().next()
+    }
+
+    // Consumes the next character and moves the current position past it
+    fn next_char(&mut self) -> Option<char> {
+        let c = self.peek_char()?;
+        self.advance(c);
+        Some(c)
     }
 
     pub fn line(&self) -> u32 {
         self.line
     }
 
-    fn create_token(input: Vec<u8>, line: u32) -> Option<Token> {
-        let token_str: String = String::from_utf8(input).unwrap_or_else(|err| {
-            println!("Error: {:?}", err);
-            "".to_owned()
-        }).to_lowercase();
-
-        let (numeric, radix) = Stream::is_numeric(&token_str);
-        if numeric {
-            return match radix {
-                // base 10
-                0 => Some(Token::ImmConst(token_str.parse::<u16>().unwrap(), line)),
-                // base 16
-                1 => Some(Token::ImmConst(u16::from_str_radix(&token_str[2..], 16).unwrap(), line)),
-                _ => None,
-            }
-
-        }
-
-        if Stream::is_register(&token_str) {
-            return match token_str.as_str() {
-                "v0"   => Some(Token::Reg(Register::V0, line)),
-                "v1"   => Some(Token::Reg(Register::V1, line)),
-                "v2"   => Some(Token::Reg(Register::V2, line)),
-                "v3"   => Some(Token::Reg(Register::V3, line)),
-                "v4"   => Some(Token::Reg(Register::V4, line)),
-                "v5"   => Some(Token::Reg(Register::V5, line)),
-                "v6"   => Some(Token::Reg(Register::V6, line)),
-                "v7"   => Some(Token::Reg(Register::V7, line)),
-                "v8"   => Some(Token::Reg(Register::V8, line)),
-                "v9"   => Some(Token::Reg(Register::V9, line)),
-                "va"   => Some(Token::Reg(Register::Va, line)),
-                "vb"   => Some(Token::Reg(Register::Vb, line)),
-                "vc"   => Some(Token::Reg(Register::Vc, line)),
-                "vd"   => Some(Token::Reg(Register::Vd, line)),
-                "ve"   => Some(Token::Reg(Register::Ve, line)),
-                "vf"   => Some(Token::Reg(Register::Vf, line)),
-                "st"   => Some(Token::St(line)),
-                "dt"   => Some(Token::Dt(line)),
-                "i"    => Some(Token::I(line)),
-                "[i]"  => Some(Token::IVal(line)),
+    // Empty span at the current position
+    fn here(&self) -> Span {
+        Span {
+            file: self.file,
+            line: self.line,
+            column: self.column,
+            start: self.offset,
+            end: self.offset,
+            expansion: None,
+        }
+    }
+
+    // Moves the current position past the given character. Columns count
+    // characters and lines end with `\n`, `\r\n` or a lone `\r`.
+    fn advance(&mut self, c: char) {
+        if c == char::REPLACEMENT_CHARACTER && self.invalid.binary_search(&self.offset).is_ok() {
+            let span = Span { end: self.offset + c.len_utf8(), ..self.here() };
+            self.invalid_at.get_or_insert(span);
+        }
+        self.offset += c.len_utf8();
+        if c == NEWLINE_CHAR || (c == RETURN_CHAR && self.peek_char() != Some(NEWLINE_CHAR)) {
+            self.line += 1;
+            self.column = 1;
+        } else {
+            self.column += 1;
+        }
+    }
+
+    // Classifies the word at `span`
+    fn create_token(&self, span: Span) -> Result<Token, LexError> {
+        let raw = self.slice(span);
+        if Stream::is_literal(raw) {
+            return match parse_literal(raw) {
+                Some(value) => Ok(self.token(TokenKind::ImmConst(value), span)),
+                None => Err(LexError::InvalidLiteral { text: raw.to_owned(), span }),
+            };
+        }
+        let kind = Stream::token_kind(raw).ok_or_else(|| {
+            LexError::InvalidToken { text: raw.to_owned(), span }
+        })?;
+        Ok(self.token(kind, span))
+    }
+
+    fn token_kind(raw: &str) -> Option<TokenKind> {
+        // Keywords are short and ASCII, so they are looked up in a copy of
+        // the word lowercased on the stack. Longer words are never keywords.
+        let mut buffer = [0; MAX_KEYWORD_LEN];
+        let token_str = match buffer.get_mut(..raw.len()) {
+            Some(lower) => {
+                lower.copy_from_slice(raw.as_bytes());
+                lower.make_ascii_lowercase();
+                str::from_utf8(lower).unwrap_or_default()
+            },
+            None => "",
+        };
+
+        if Stream::is_register(token_str) {
+            return match token_str {
+                "v0"   => Some(TokenKind::Reg(Register::V0)),
+                "v1"   => Some(TokenKind::Reg(Register::V1)),
+                "v2"   => Some(TokenKind::Reg(Register::V2)),
+                "v3"   => Some(TokenKind::Reg(Register::V3)),
+                "v4"   => Some(TokenKind::Reg(Register::V4)),
+                "v5"   => Some(TokenKind::Reg(Register::V5)),
+                "v6"   => Some(TokenKind::Reg(Register::V6)),
+                "v7"   => Some(TokenKind::Reg(Register::V7)),
+                "v8"   => Some(TokenKind::Reg(Register::V8)),
+                "v9"   => Some(TokenKind::Reg(Register::V9)),
+                "va"   => Some(TokenKind::Reg(Register::Va)),
+                "vb"   => Some(TokenKind::Reg(Register::Vb)),
+                "vc"   => Some(TokenKind::Reg(Register::Vc)),
+                "vd"   => Some(TokenKind::Reg(Register::Vd)),
+                "ve"   => Some(TokenKind::Reg(Register::Ve)),
+                "vf"   => Some(TokenKind::Reg(Register::Vf)),
+                "st"   => Some(TokenKind::St),
+                "dt"   => Some(TokenKind::Dt),
+                "i"    => Some(TokenKind::I),
+                "[i]"  => Some(TokenKind::IVal),
                 _      => None
             }
         }
 
-        match token_str.as_str() {
-            "add"   => Some(Token::Opcode(Mnemonic::Add, line)),
-            "and"   => Some(Token::Opcode(Mnemonic::And, line)),
-            "call"  => Some(Token::Opcode(Mnemonic::Call, line)),
-            "cls"   => Some(Token::Opcode(Mnemonic::Cls, line)),
-            "drw"   => Some(Token::Opcode(Mnemonic::Drw, line)),
-            "jp"    => Some(Token::Opcode(Mnemonic::Jp, line)),
-            "ld"    => Some(Token::Opcode(Mnemonic::Ld, line)),
-            "or"    => Some(Token::Opcode(Mnemonic::Or, line)),
-            "ret"   => Some(Token::Opcode(Mnemonic::Ret, line)),
-            "rnd"   => Some(Token::Opcode(Mnemonic::Rnd, line)),
-            "se"    => Some(Token::Opcode(Mnemonic::Se, line)),
-            "shl"   => Some(Token::Opcode(Mnemonic::Shl, line)),
-            "shr"   => Some(Token::Opcode(Mnemonic::Shr, line)),
-            "sknp"  => Some(Token::Opcode(Mnemonic::Sknp, line)),
-            "skp"   => Some(Token::Opcode(Mnemonic::Skp, line)),
-            "sne"   => Some(Token::Opcode(Mnemonic::Sne, line)),
-            "sub"   => Some(Token::Opcode(Mnemonic::Sub, line)),
-            "subn"  => Some(Token::Opcode(Mnemonic::Subn, line)),
-            "sys"   => Some(Token::Opcode(Mnemonic::Sys, line)),
-            "xor"   => Some(Token::Opcode(Mnemonic::Xor, line)),
-            "f"     => Some(Token::F(line)),
-            "b"     => Some(Token::B(line)),
-            "k"     => Some(Token::K(line)),
-            _       => None
+        match token_str {
+            "add"   => Some(TokenKind::Opcode(Mnemonic::Add)),
+            "and"   => Some(TokenKind::Opcode(Mnemonic::And)),
+            "call"  => Some(TokenKind::Opcode(Mnemonic::Call)),
+            "cls"   => Some(TokenKind::Opcode(Mnemonic::Cls)),
+            "drw"   => Some(TokenKind::Opcode(Mnemonic::Drw)),
+            "jp"    => Some(TokenKind::Opcode(Mnemonic::Jp)),
+            "ld"    => Some(TokenKind::Opcode(Mnemonic::Ld)),
+            "or"    => Some(TokenKind::Opcode(Mnemonic::Or)),
+            "ret"   => Some(TokenKind::Opcode(Mnemonic::Ret)),
+            "rnd"   => Some(TokenKind::Opcode(Mnemonic::Rnd)),
+            "se"    => Some(TokenKind::Opcode(Mnemonic::Se)),
+            "shl"   => Some(TokenKind::Opcode(Mnemonic::Shl)),
+            "shr"   => Some(TokenKind::Opcode(Mnemonic::Shr)),
+            "sknp"  => Some(TokenKind::Opcode(Mnemonic::Sknp)),
+            "skp"   => Some(TokenKind::Opcode(Mnemonic::Skp)),
+            "sne"   => Some(TokenKind::Opcode(Mnemonic::Sne)),
+            "sub"   => Some(TokenKind::Opcode(Mnemonic::Sub)),
+            "subn"  => Some(TokenKind::Opcode(Mnemonic::Subn)),
+            "sys"   => Some(TokenKind::Opcode(Mnemonic::Sys)),
+            "xor"   => Some(TokenKind::Opcode(Mnemonic::Xor)),
+            "db" | ".byte" => Some(TokenKind::Opcode(Mnemonic::Db)),
+            "dw" | ".word" => Some(TokenKind::Opcode(Mnemonic::Dw)),
+            "org"   => Some(TokenKind::Opcode(Mnemonic::Org)),
+            "align" => Some(TokenKind::Opcode(Mnemonic::Align)),
+            "equ"   => Some(TokenKind::Opcode(Mnemonic::Equ)),
+            ".const" => Some(TokenKind::Opcode(Mnemonic::Const)),
+            ".sprite" => Some(TokenKind::Opcode(Mnemonic::Sprite)),
+            "incsprite" => Some(TokenKind::Opcode(Mnemonic::IncSprite)),
+            "include" => Some(TokenKind::Opcode(Mnemonic::Include)),
+            "incbin" => Some(TokenKind::Opcode(Mnemonic::IncBin)),
+            "macro" => Some(TokenKind::Opcode(Mnemonic::Macro)),
+            "endm" => Some(TokenKind::Opcode(Mnemonic::Endm)),
+            "if" => Some(TokenKind::Opcode(Mnemonic::If)),
+            "elseif" => Some(TokenKind::Opcode(Mnemonic::ElseIf)),
+            "else" => Some(TokenKind::Opcode(Mnemonic::Else)),
+            "endif" => Some(TokenKind::Opcode(Mnemonic::EndIf)),
+            "ifdef" => Some(TokenKind::Opcode(Mnemonic::IfDef)),
+            "ifndef" => Some(TokenKind::Opcode(Mnemonic::IfNDef)),
+            "rept" => Some(TokenKind::Opcode(Mnemonic::Rept)),
+            "for" => Some(TokenKind::Opcode(Mnemonic::For)),
+            "endr" => Some(TokenKind::Opcode(Mnemonic::Endr)),
+            ".text" | ".ascii" => Some(TokenKind::Opcode(Mnemonic::Text)),
+            ".textw" => Some(TokenKind::Opcode(Mnemonic::TextW)),
+            ".charmap" => Some(TokenKind::Opcode(Mnemonic::CharMap)),
+            "f"     => Some(TokenKind::F),
+            "b"     => Some(TokenKind::B),
+            "k"     => Some(TokenKind::K),
+            _       => {
+                if raw.ends_with(LABEL_CHAR) {
+                    let name = &raw[..raw.len() - 1];
+                    if Stream::is_identifier(name) || (name.starts_with('.') && Stream::is_reference(name)) {
+                        return Some(TokenKind::Label);
+                    }
+                    return None;
+                }
+                if Stream::is_reference(raw) {
+                    return Some(TokenKind::Ident);
+                }
+                if raw.contains(PIXEL_OFF) && raw.chars().all(Stream::is_pixel) {
+                    return Some(TokenKind::Pixels);
+                }
+                None
+            }
+        }
+    }
+
+    fn is_pixel(c: char) -> bool {
+        c == PIXEL_OFF || c == PIXEL_ON || c == PIXEL_ON.to_ascii_lowercase()
+    }
+
+    // Numeric literals start with a digit or a hexadecimal prefix
+    fn is_literal(input: &str) -> bool {
+        input.starts_with(|c: char| c.is_ascii_digit() || c == '$' || c == '#')
+    }
+
+    // Any whitespace separates words, line ends and non-breaking spaces
+    // included
+    fn is_separator(c: char) -> bool {
+        c.is_whitespace()
+    }
+
+    fn is_line_end(c: char) -> bool {
+        c == NEWLINE_CHAR || c == RETURN_CHAR
+    }
+
+    // Characters that make up a token of their own
+    fn is_punctuation(c: char) -> bool {
+        matches!(c, COMMA_CHAR | '=' | '(' | ')' | '+' | '-' | '*' | '/' |
+                            '%' | '&' | '|' | '^' | '~' | '<' | '>' | '!')
+    }
+
+    // Identifiers start with a letter or an underscore followed by any
+    // number of letters, digits and underscores
+    fn is_identifier(input: &str) -> bool {
+        let mut chars = input.chars();
+        match chars.next() {
+            Some(c) if c.is_ascii_alphabetic() || c == '_' => {
+                chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+            },
+            _ => false
         }
     }
 
-    fn is_separator(b: u8) -> bool {
-        match b as char {
-            COMMA_CHAR | SPACE_CHAR | NEWLINE_CHAR | TAB_CHAR => { true }
-            _ => { false }
+    // A reference to a label is an identifier, a local label such as
+    // `.loop` or a local label qualified by its global label, `main.loop`
+    fn is_reference(input: &str) -> bool {
+        match input.split_once('.') {
+            Some((global, local)) => {
+                (global.is_empty() || Stream::is_identifier(global)) && Stream::is_identifier(local)
+            },
+            None => Stream::is_identifier(input),
         }
     }
 
@@ -204,577 +743,2451 @@ impl Stream {
         input == "vf" || input == "st" || input == "dt" ||
         input == "i" || input == "[i]"
     }
+}
 
-    // Returns a tuple of boolean and integer
-    // The input is numeric if the boolean component of the result is true
-    // Integer component of result:
-    //   0 => base 10
-    //   1 => base 16
-    fn is_numeric(input: &str) -> (bool, u8) {
-        if input.len() < 1 {
-            return (false, ::std::u8::MAX);
-        }
+impl Iterator for Stream {
+    type Item = Result<Token, LexError>;
 
-        let in_bytes = input.as_bytes();
-        if !Stream::is_ascii_numeric(in_bytes[0]) {
-            return (false, ::std::u8::MAX);
+    /// Returns the next token, an error for a word that is not a valid
+    /// token or for input that cannot be read, or `None` at the end of the
+    /// input. Reading goes on after an error.
+    fn next(&mut self) -> Option<Result<Token, LexError>> {
+        if let Some(error) = self.error.take() {
+            return Some(Err(LexError::Io(error)));
+        }
+        let next = self.scan();
+        // A token with bytes that are not UTF-8 in it is reported at the
+        // first of them instead
+        match self.invalid_at.take() {
+            Some(span) => Some(Err(LexError::InvalidUtf8 { span })),
+            None => next,
         }
+    }
+}
 
-        // hex
-        if input.starts_with("0x") {
-            for i in 2..(in_bytes.len()) {
-                if !Stream::is_ascii_hex(in_bytes[i]) {
-                    return (false, ::std::u8::MAX);
-                }
-                return (true, 1);
+impl Stream {
+    // Reads the next token after any separators
+    fn scan(&mut self) -> Option<Result<Token, LexError>> {
+        while let Some(c) = self.peek_char() {
+            if c == COMMENT_CHAR {
+                return Some(Ok(self.comment()));
             }
-        } else {
-            for b in in_bytes {
-                if !Stream::is_ascii_numeric(*b) {
-                    return (false, ::std::u8::MAX);
-                }
-                return (true, 0);
+            if c == QUOTE_CHAR {
+                return Some(self.char_literal());
+            }
+            if c == STRING_CHAR {
+                return Some(self.string_literal());
+            }
+            if Stream::is_punctuation(c) {
+                return Some(self.punctuation());
             }
+            if !Stream::is_separator(c) {
+                break;
+            }
+            self.next_char();
         }
-        return (false, ::std::u8::MAX);
+        self.word()
     }
+}
 
-    fn is_ascii_numeric(input: u8) -> bool {
-        input >= 48 && input <= 57
+impl<'a> From<&'a str> for Stream {
+    fn from(source: &'a str) -> Stream {
+        Stream::from_source(source.into(), 0)
     }
+}
+
+/// Settings that change how a program is assembled
+#[derive(Debug, Clone)]
+pub struct Options {
+    /// Masks operands that do not fit in their instruction to their width
+    /// and reports them as warnings rather than errors
+    pub truncate: bool,
+    /// Address the program is loaded at, which labels are relative to
+    pub base: u16,
+    /// Constants defined in addition to those of the program. A name given
+    /// more than once takes its last value.
+    pub defines: Vec<(String, i64)>,
+    /// Directories searched for included files that are not found next to
+    /// the file naming them
+    pub include_dirs: Vec<PathBuf>,
+}
 
-    fn is_ascii_hex(input: u8) -> bool {
-        (input >= 48 && input <= 57) || ((input >= 97 && input <= 102) || (input >= 65 && input <= 70))
+impl Default for Options {
+    fn default() -> Options {
+        Options {
+            truncate: false,
+            base: LOAD_ADDRESS,
+            defines: vec![],
+            include_dirs: vec![],
+        }
     }
 }
 
-pub fn code_gen(tokens: &Vec<Token>) -> Result<Vec<u16>, Token> {
-    let mut result: Vec<u16> = vec![];
+/// The files a program is assembled from and the macro expansions in them,
+/// which spans refer to by index. The input comes first, followed by the
+/// files it includes.
+#[derive(Debug, Default, Clone)]
+pub struct Files {
+    paths: Vec<PathBuf>,
+    // Location of the `INCLUDE` of every file, `None` for the input
+    included_from: Vec<Option<Span>>,
+    expansions: Vec<Expansion>,
+}
 
-    let mut curr_opcode: Option<&Mnemonic> = None;
-    let mut last_token: Option<TokenRef> = None;
-    let mut draw_first_reg: Option<(&Register, u32)> = None;
+/// A use of a macro
+#[derive(Debug, Clone)]
+pub struct Expansion {
+    pub name: String,
+    /// Location of the macro name where it is used
+    pub call: Span,
+}
 
-    #[derive(Debug, Copy, Clone)]
-    enum TokenRef<'a> {
-        Reg(&'a Register, u32),
-        F(u32), B(u32), I(u32),
-        St(u32), Dt(u32), IVal(u32),
+impl Files {
+    pub fn new() -> Files {
+        Files { paths: vec![], included_from: vec![], expansions: vec![] }
     }
 
-    for token in tokens {
-        let temp_last_token: Option<TokenRef>;
-        match token {
-            &Token::Reg(ref nr, nl) => {
-                match curr_opcode {
-                    Some(&Mnemonic::Ld) => {
-                        match last_token {
-                            Some(TokenRef::Reg(ref or, _)) => {
-                                result.push((0x8u16 << 12) | ((or.number() as u16) << 8) | ((nr.number() as u16) << 4) | 0x0);
-                                temp_last_token = None;
-                                curr_opcode = None;
-                            },
-                            Some(TokenRef::B(_)) => {
-                                result.push((0xfu16 << 12) | ((nr.number() as u16) << 8) | 0x33);
-                                temp_last_token = None;
-                                curr_opcode = None;
-                            },
-                            Some(TokenRef::F(_)) => {
-                                result.push((0xfu16 << 12) | ((nr.number() as u16) << 8) | 0x29);
-                                temp_last_token = None;
-                                curr_opcode = None;
-                            },
-                            Some(TokenRef::Dt(_)) => {
-                                result.push((0xfu16 << 12) | ((nr.number() as u16) << 8) | 0x15);
-                                temp_last_token = None;
-                                curr_opcode = None;
-                            },
-                            Some(TokenRef::St(_)) => {
-                                result.push((0xfu16 << 12) | ((nr.number() as u16) << 8) | 0x18);
-                                temp_last_token = None;
-                                curr_opcode = None;
-                            },
-                            Some(TokenRef::IVal(_)) => {
-                                result.push((0xfu16 << 12) | ((nr.number() as u16) << 8) | 0x55);
-                                temp_last_token = None;
-                                curr_opcode = None;
-                            },
-                            None => { temp_last_token = Some(TokenRef::Reg(nr, nl)) },
-                            _ => {
-                                return Err(Token::Reg(nr.clone(), nl));
-                            }
-                        }
-                    },
-                    Some(&Mnemonic::Se) => {
-                        match last_token {
-                            Some(TokenRef::Reg(ref or, _)) => {
-                                result.push((0x5u16 << 12) | ((or.number() as u16) << 8) | ((nr.number() as u16) << 4) | 0x0);
-                                temp_last_token = None;
-                                curr_opcode = None;
-                            },
-                            None => { temp_last_token = Some(TokenRef::Reg(nr, nl)) },
-                            _ => {
-                                return Err(Token::Reg(nr.clone(), nl));
-                            }
-                        }
-                    },
-                    Some(&Mnemonic::Sne) => {
-                        match last_token {
-                            Some(TokenRef::Reg(ref or, _)) => {
-                                result.push((0x9u16 << 12) | ((or.number() as u16) << 8) | ((nr.number() as u16) << 4) | 0x0);
-                                temp_last_token = None;
-                                curr_opcode = None;
-                            },
-                            None => { temp_last_token = Some(TokenRef::Reg(nr, nl)) },
-                            _ => {
-                                return Err(Token::Reg(nr.clone(), nl));
-                            }
-                        }
-                    },
-                    Some(&Mnemonic::Skp) => {
-                        match last_token {
-                            None => {
-                                result.push((0xeu16 << 12) | ((nr.number() as u16) << 8) | 0x9e);
-                                temp_last_token = None;
-                                curr_opcode = None;
+    /// Records a macro expansion and returns its index
+    pub fn add_expansion(&mut self, name: &str, call: Span) -> usize {
+        self.expansions.push(Expansion { name: name.to_owned(), call });
+        self.expansions.len() - 1
+    }
+
+    pub fn expansion(&self, index: usize) -> Option<&Expansion> {
+        self.expansions.get(index)
+    }
+
+    /// Adds a file and returns its index
+    pub fn add<P: Into<PathBuf>>(&mut self, path: P) -> usize {
+        self.paths.push(path.into());
+        self.included_from.push(None);
+        self.paths.len() - 1
+    }
+
+    /// Adds a file included by the `INCLUDE` at `from` and returns its index
+    pub fn add_included<P: Into<PathBuf>>(&mut self, path: P, from: Span) -> usize {
+        let file = self.add(path);
+        self.included_from[file] = Some(from);
+        file
+    }
+
+    /// Location of the `INCLUDE` of a file, `None` for the input
+    pub fn included_from(&self, file: usize) -> Option<Span> {
+        self.included_from.get(file).cloned().flatten()
+    }
+
+    /// The locations of the `INCLUDE`s leading to the file of `span`,
+    /// outermost first, followed by `span`. These order spans the way the
+    /// input is read.
+    pub fn origin(&self, span: Span) -> Vec<Span> {
+        let mut origin = vec![span];
+        while let Some(include) = self.included_from(origin[origin.len() - 1].file) {
+            origin.push(include);
+        }
+        origin.reverse();
+        origin
+    }
+
+    pub fn path(&self, file: usize) -> Option<&Path> {
+        self.paths.get(file).map(PathBuf::as_path)
+    }
+
+    pub fn len(&self) -> usize {
+        self.paths.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.paths.is_empty()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Path> {
+        self.paths.iter().map(PathBuf::as_path)
+    }
+}
+
+/// Parses a numeric literal: decimal, hexadecimal prefixed with `0x`, `$`
+/// or `#`, octal prefixed with `0o` or binary prefixed with `0b`, with `_`
+/// allowed between digits. A character in single quotes, e.g. `'A'` or
+/// `'\n'`, stands for its code. Returns `None` if the literal is malformed
+/// or does not fit in 64 bits.
+pub fn parse_literal(text: &str) -> Option<i64> {
+    if text.starts_with(QUOTE_CHAR) {
+        return parse_char(text).map(|c| c as i64);
+    }
+    let (digits, radix) = match *text.as_bytes() {
+        [b'0', b'x' | b'X', ..] => (&text[2..], 16),
+        [b'$' | b'#', ..] => (&text[1..], 16),
+        [b'0', b'o' | b'O', ..] => (&text[2..], 8),
+        [b'0', b'b' | b'B', ..] => (&text[2..], 2),
+        _ => (text, 10),
+    };
+    if digits.is_empty() || digits.starts_with('_') || digits.ends_with('_') {
+        return None;
+    }
+    digits.chars().filter(|&c| c != '_').try_fold(0i64, |value, c| {
+        value.checked_mul(radix as i64)?.checked_add(c.to_digit(radix)? as i64)
+    })
+}
+
+// Parses a quoted character with an optional backslash escape
+fn parse_char(text: &str) -> Option<char> {
+    let inner = text.strip_prefix(QUOTE_CHAR)?.strip_suffix(QUOTE_CHAR)?;
+    let mut chars = inner.chars();
+    let c = match chars.next()? {
+        '\\' => unescape(chars.next()?)?,
+        c => c,
+    };
+    if chars.next().is_some() {
+        return None;
+    }
+    Some(c)
+}
+
+// The character a backslash followed by `c` stands for
+fn unescape(c: char) -> Option<char> {
+    match c {
+        'n' => Some('\n'),
+        't' => Some('\t'),
+        'r' => Some('\r'),
+        '0' => Some('\0'),
+        '\\' | '\'' | '"' => Some(c),
+        _ => None,
+    }
+}
+
+// The contents of a string with its escapes resolved. Escapes were checked
+// when it was read.
+fn unescape_str(inner: &str) -> String {
+    let mut chars = inner.chars();
+    let mut string = String::with_capacity(inner.len());
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => string.extend(chars.next().and_then(unescape)),
+            c => string.push(c),
+        }
+    }
+    string
+}
+
+// Escapes backslashes and quotes so that the string reads back as `text`
+fn escape_str(text: &str) -> String {
+    text.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+/// Tokenizes and parses the whole input and generates code for it,
+/// reporting every error found rather than stopping at the first. Problems
+/// that do not stop the program from being assembled go into `warnings`.
+/// `files` holds the path of the input, if it has one, and receives the
+/// files it includes.
+pub fn assemble(stream: &mut Stream, options: &Options, files: &mut Files, warnings: &mut Errors)
+    -> Result<Assembly, Errors>
+{
+    let mut errors = Errors::new();
+    if files.is_empty() {
+        // Files included by an input without a path are searched from the
+        // current directory
+        files.add("");
+    }
+    let tokens = tokenize(stream, &mut errors);
+    let tokens = preprocess(tokens, options, files, &mut errors);
+    let mut statements = parse(&tokens, &mut errors);
+    qualify_labels(&mut statements, &mut errors);
+    match code_gen(&statements, options, warnings) {
+        Ok(assembly) if errors.is_empty() => Ok(assembly),
+        Ok(_) => {
+            errors.sort_by_origin(files);
+            Err(errors)
+        },
+        Err(more) => {
+            errors.extend(more);
+            errors.sort_by_origin(files);
+            Err(errors)
+        }
+    }
+}
+
+/// Reads every token from the stream. A line containing an invalid token is
+/// dropped, keeping only its label definitions, and the error is recorded.
+pub fn tokenize(stream: &mut Stream, errors: &mut Errors) -> Vec<Token> {
+    let mut tokens: Vec<Token> = vec![];
+    let mut skip_line = None;
+
+    for next in stream {
+        match next {
+            Ok(ref token) if skip_line == Some(token.span.line) => {},
+            Ok(token) => tokens.push(token),
+            Err(err) => {
+                if let Some(span) = err.span() {
+                    discard_line(&mut tokens, span.line);
+                    skip_line = Some(span.line);
+                }
+                errors.push(err.into());
+            }
+        }
+    }
+    tokens
+}
+
+// Reads the tokens of the file named on an `INCLUDE` line and returns them
+// along with the canonical path of the file. `open` holds the files being
+// included, outermost first.
+fn include_file(code: &[&Token], include_dirs: &[PathBuf], files: &mut Files, open: &[PathBuf],
+                errors: &mut Errors) -> Result<(Vec<Token>, PathBuf), AssembleError> {
+    let mut cursor = Cursor { tokens: &code[1..], pos: 0 };
+    let operands = cursor.operands()?;
+    let (name, span) = match operands.args[..] {
+        [Arg::Str(ref name)] => (name, operands.spans[0]),
+        _ => return Err(operand_error(Mnemonic::Include, code[0].span, &operands)),
+    };
+    let read_error = |error| AssembleError::ReadFile { path: name.clone(), error, span };
+    let path = find_file(name, files.path(span.file), include_dirs)
+        .ok_or_else(|| read_error(io::Error::new(io::ErrorKind::NotFound, "file not found")))?;
+    let canonical = fs::canonicalize(&path).map_err(read_error)?;
+    if open.contains(&canonical) {
+        return Err(AssembleError::IncludeCycle { path: name.clone(), span });
+    }
+    let input = File::open(&path).map_err(read_error)?;
+
+    let mut stream = Stream::with_file(input, files.add_included(path, code[0].span));
+    Ok((tokenize(&mut stream, errors), canonical))
+}
+
+// Looks for a file next to the file `from` that names it, then in the
+// include directories
+fn find_file(name: &str, from: Option<&Path>, dirs: &[PathBuf]) -> Option<PathBuf> {
+    let here = from.and_then(Path::parent).unwrap_or_else(|| Path::new(""));
+    iter::once(here).chain(dirs.iter().map(PathBuf::as_path))
+        .map(|dir| dir.join(name))
+        .find(|path| path.is_file())
+}
+
+// A macro defined by `MACRO`
+struct Macro {
+    params: Vec<String>,
+    // Tokens between the `MACRO` and `ENDM` lines
+    body: Vec<Token>,
+    // Location of the name in the definition
+    span: Span,
+}
+
+// A `MACRO`, `REPT` or `FOR` block whose body is being read
+struct Block {
+    // The opening line without its labels and comment
+    code: Vec<Token>,
+    body: Vec<Token>,
+    // Number of `REPT` and `FOR` blocks opened in the body of a `REPT` or
+    // `FOR` block and not closed yet
+    nesting: usize,
+}
+
+impl Block {
+    fn mnemonic(&self) -> Mnemonic {
+        match self.code[0].kind {
+            TokenKind::Opcode(mnemonic) => mnemonic,
+            _ => Mnemonic::Macro,
+        }
+    }
+}
+
+// An `IF`, `IFDEF` or `IFNDEF` block
+struct Condition {
+    mnemonic: Mnemonic,
+    // Location of the opening directive
+    span: Span,
+    // Whether the lines of the current branch are assembled
+    active: bool,
+    // Whether a branch was taken already, or none can be because the
+    // enclosing block is skipped
+    done: bool,
+    // Whether the `ELSE` branch was reached
+    in_else: bool,
+}
+
+/// Runs the directives that act on lines rather than emit code, in the
+/// order of the lines:
+///
+/// - `MACRO name params ... ENDM` defines a macro. A later line starting
+///   with its name is replaced by its body, each parameter being
+///   substituted by the tokens of the matching argument. Labels defined in a
+///   macro are local to each of its expansions.
+/// - `IF expr`, `IFDEF NAME` and `IFNDEF NAME` blocks, with optional
+///   `ELSEIF expr` and `ELSE` branches up to `ENDIF`, keep the lines of the
+///   first branch whose condition holds. Conditions can refer to the
+///   constants defined before them and to those of the options.
+/// - `REPT count ... ENDR` repeats its lines `count` times and `FOR name =
+///   first TO last ... ENDR` once for every value from `first` to `last`,
+///   which replaces `name`. Labels are local to each repetition.
+/// - `INCLUDE "file"` is replaced by the lines of the file, keeping its
+///   labels, so a file included in a skipped branch is never read. Files
+///   are searched next to the file naming them, then in the include
+///   directories, and are added to `files`. A file that includes itself,
+///   directly or not, is an error. The paths given to `INCBIN` and
+///   `INCSPRITE` are replaced by the files they name the same way.
+///
+/// Macro uses and repetitions are recorded in `files` as expansions, whose
+/// tokens are located in the body they come from.
+pub fn preprocess(tokens: Vec<Token>, options: &Options, files: &mut Files, errors: &mut Errors) -> Vec<Token> {
+    let mut symbols: Symbols = HashMap::new();
+    for &(ref name, value) in &options.defines {
+        symbols.insert(name.clone(), Symbol::Predefined(value));
+    }
+    let open = files.path(0).and_then(|path| fs::canonicalize(path).ok()).into_iter().collect();
+    let mut preprocessor = Preprocessor {
+        macros: HashMap::new(),
+        symbols,
+        conditions: vec![],
+        block: None,
+        include_dirs: &options.include_dirs,
+        open,
+        expansions: 0,
+        expanded: 0,
+        halted: false,
+        files,
+        errors,
+        output: Vec::with_capacity(tokens.len()),
+    };
+    for line in lines(&tokens) {
+        preprocessor.line(line, 0);
+    }
+    preprocessor.finish()
+}
+
+struct Preprocessor<'a> {
+    macros: HashMap<String, Rc<Macro>>,
+    // Constants defined so far, for evaluating conditions
+    symbols: Symbols,
+    // Conditional blocks the current line is in, innermost last
+    conditions: Vec<Condition>,
+    // Block whose body is being read
+    block: Option<Block>,
+    include_dirs: &'a [PathBuf],
+    // Canonical paths of the files being included, outermost first
+    open: Vec<PathBuf>,
+    // Macro expansions and repetitions so far
+    expansions: usize,
+    // Tokens they produced
+    expanded: usize,
+    // Whether the expansion the current line is in was stopped by an error,
+    // so that the rest of it is dropped
+    halted: bool,
+    files: &'a mut Files,
+    errors: &'a mut Errors,
+    output: Vec<Token>,
+}
+
+impl<'a> Preprocessor<'a> {
+    // `depth` is the number of macro expansions the line is nested in
+    fn line(&mut self, line: &[Token], depth: usize) {
+        if depth == 0 {
+            self.halted = false;
+        }
+        let code = code_tokens(line);
+        let directive = match code.first() {
+            Some(&&Token { kind: TokenKind::Opcode(mnemonic), .. }) => Some(mnemonic),
+            _ => None,
+        };
+
+        if let Some(ref mut block) = self.block {
+            let repeat = block.mnemonic() != Mnemonic::Macro;
+            match directive {
+                Some(Mnemonic::Endm) if !repeat => {
+                    block.body.extend(labels(line));
+                    let block = self.block.take().unwrap();
+                    self.define_macro(block);
+                },
+                Some(Mnemonic::Macro) if !repeat => {
+                    self.errors.push(AssembleError::NestedMacro { span: code[0].span });
+                },
+                Some(Mnemonic::Endr) if repeat && block.nesting == 0 => {
+                    block.body.extend(labels(line));
+                    let block = self.block.take().unwrap();
+                    self.repeat(block, depth);
+                },
+                _ => {
+                    match directive {
+                        Some(Mnemonic::Rept | Mnemonic::For) if repeat => block.nesting += 1,
+                        Some(Mnemonic::Endr) if repeat => block.nesting -= 1,
+                        _ => {},
+                    }
+                    block.body.extend(line.iter().cloned());
+                },
+            }
+            return;
+        }
+        if let Some(mnemonic @ (Mnemonic::If | Mnemonic::IfDef | Mnemonic::IfNDef |
+                                Mnemonic::ElseIf | Mnemonic::Else | Mnemonic::EndIf)) = directive {
+            if self.active() {
+                self.output.extend(labels(line));
+            }
+            self.condition(mnemonic, &code);
+            return;
+        }
+        if !self.active() || self.halted {
+            return;
+        }
+
+        match directive {
+            Some(Mnemonic::Macro | Mnemonic::Rept | Mnemonic::For) => {
+                self.output.extend(labels(line));
+                self.block = Some(Block { code: code.into_iter().cloned().collect(), body: vec![], nesting: 0 });
+            },
+            Some(mnemonic @ (Mnemonic::Endm | Mnemonic::Endr)) => {
+                self.output.extend(labels(line));
+                self.errors.push(AssembleError::UnmatchedBlockEnd { mnemonic, span: code[0].span });
+            },
+            Some(Mnemonic::Include) => {
+                self.output.extend(labels(line));
+                self.include(&code, depth);
+            },
+            Some(Mnemonic::IncBin | Mnemonic::IncSprite) => {
+                let (files, include_dirs) = (&*self.files, self.include_dirs);
+                self.output.extend(line.iter().map(|token| match token.kind {
+                    TokenKind::Str => {
+                        let name = unescape_str(token.name());
+                        match find_file(&name, files.path(token.span.file), include_dirs) {
+                            Some(path) => {
+                                let text = format!("\"{}\"", escape_str(&path.display().to_string()));
+                                Token::with_text(TokenKind::Str, token.span, text)
                             },
-                            _ => {
-                                return Err(Token::Reg(nr.clone(), nl));
-                            }
+                            None => token.clone(),
                         }
                     },
-                    Some(&Mnemonic::Sknp) => {
-                        match last_token {
-                            None => {
-                                result.push((0xeu16 << 12) | ((nr.number() as u16) << 8) | 0xa1);
-                                temp_last_token = None;
-                                curr_opcode = None;
-                            },
-                            _ => {
-                                return Err(Token::Reg(nr.clone(), nl));
-                            }
-                        }
-                    },
-                    Some(&Mnemonic::Add) => {
-                        match last_token {
-                            Some(TokenRef::Reg(ref or, _)) => {
-                                result.push((0x8u16 << 12) | ((or.number() as u16) << 8) | ((nr.number() as u16) << 4) | 0x4);
-                                temp_last_token = None;
-                                curr_opcode = None;
-                            },
-                            None => { temp_last_token = Some(TokenRef::Reg(nr, nl)) },
-                            _ => {
-                                return Err(Token::Reg(nr.clone(), nl));
-                            }
-                        }
-                    },
-                    Some(&Mnemonic::Sub) => {
-                        match last_token {
-                            Some(TokenRef::Reg(ref or, _)) => {
-                                result.push((0x8u16 << 12) | ((or.number() as u16) << 8) | ((nr.number() as u16) << 4) | 0x5);
-                                temp_last_token = None;
-                                curr_opcode = None;
-                            },
-                            None => { temp_last_token = Some(TokenRef::Reg(nr, nl)) },
-                            _ => {
-                                return Err(Token::Reg(nr.clone(), nl));
-                            }
-                        }
-                    },
-                    Some(&Mnemonic::Subn) => {
-                        match last_token {
-                            Some(TokenRef::Reg(ref or, _)) => {
-                                result.push((0x8u16 << 12) | ((or.number() as u16) << 8) | ((nr.number() as u16) << 4) | 0x7);
-                                temp_last_token = None;
-                                curr_opcode = None;
-                            },
-                            None => { temp_last_token = Some(TokenRef::Reg(nr, nl)) },
-                            _ => {
-                                return Err(Token::Reg(nr.clone(), nl));
-                            }
-                        }
-                    },
-                    Some(&Mnemonic::Rnd) => {
-                        match last_token {
-                            None => { temp_last_token = Some(TokenRef::Reg(nr, nl)) },
-                            _ => {
-                                return Err(Token::Reg(nr.clone(), nl));
-                            }
-                        }
-                    },
-                    Some(&Mnemonic::Or) => {
-                        match last_token {
-                            Some(TokenRef::Reg(ref or, _)) => {
-                                result.push((0x8u16 << 12) | ((or.number() as u16) << 8) | ((nr.number() as u16) << 4) | 0x1);
-                                temp_last_token = None;
-                                curr_opcode = None;
-                            },
-                            None => { temp_last_token = Some(TokenRef::Reg(nr, nl)) },
-                            _ => {
-                                return Err(Token::Reg(nr.clone(), nl));
-                            }
-                        }
-                    },
-                    Some(&Mnemonic::And) => {
-                        match last_token {
-                            Some(TokenRef::Reg(ref or, _)) => {
-                                result.push((0x8u16 << 12) | ((or.number() as u16) << 8) | ((nr.number() as u16) << 4) | 0x2);
-                                temp_last_token = None;
-                                curr_opcode = None;
-                            },
-                            None => { temp_last_token = Some(TokenRef::Reg(nr, nl)) },
-                            _ => {
-                                return Err(Token::Reg(nr.clone(), nl));
-                            }
-                        }
-                    },
-                    Some(&Mnemonic::Xor) => {
-                        match last_token {
-                            Some(TokenRef::Reg(ref or, _)) => {
-                                result.push((0x8u16 << 12) | ((or.number() as u16) << 8) | ((nr.number() as u16) << 4) | 0x3);
-                                temp_last_token = None;
-                                curr_opcode = None;
-                            },
-                            None => { temp_last_token = Some(TokenRef::Reg(nr, nl)); },
-                            _ => {
-                                return Err(Token::Reg(nr.clone(), nl));
-                            }
-                        }
-                    },
-                    Some(&Mnemonic::Shr) => {
-                        match last_token {
-                            Some(TokenRef::Reg(ref or, _)) => {
-                                result.push((0x8u16 << 12) | ((or.number() as u16) << 8) | ((nr.number() as u16) << 4) | 0x06);
-                                curr_opcode = None;
-                                temp_last_token = None;
-                            },
-                            None => {
-                                temp_last_token = Some(TokenRef::Reg(nr, nl));
-                            },
-                            _ => {
-                                return Err(Token::Reg(nr.clone(), nl));
-                            }
-                        }
-                    },
-                    Some(&Mnemonic::Shl) => {
-                        match last_token {
-                            Some(TokenRef::Reg(ref or, _)) => {
-                                result.push((0x8u16 << 12) | ((or.number() as u16) << 8) | ((nr.number() as u16) << 8) | 0x0e);
-                                curr_opcode = None;
-                                temp_last_token = None;
-                            },
-                            None => {
-                                temp_last_token = Some(TokenRef::Reg(nr, nl));
-                            },
-                            _ => {
-                                return Err(Token::Reg(nr.clone(), nl));
-                            }
-                        }
-                    },
-                    Some(&Mnemonic::Cls) => {
-                        match last_token {
-                            None => {
-                                result.push(0u16 | 0xe0);
-                                temp_last_token = None;
-                                curr_opcode = None;
-                            },
-                            _ => {
-                                return Err(Token::Reg(nr.clone(), nl));
-                            }
-                        }
-                    },
-                    Some(&Mnemonic::Drw) => {
-                        match last_token {
-                            Some(TokenRef::Reg(ref or, ol)) => {
-                                draw_first_reg = Some((or, ol));
-                                temp_last_token = Some(TokenRef::Reg(nr, nl));
-                            },
-                            None => { temp_last_token = Some(TokenRef::Reg(nr, nl)); },
-                            _ => {
-                                return Err(Token::Reg(nr.clone(), nl));
-                            }
-                        }
-                    },
-                    Some(&Mnemonic::Ret) => {
-                        match last_token {
-                            None => {
-                                result.push(0u16 | 0xee);
-                                temp_last_token = None;
-                            },
-                            _ => {
-                                return Err(Token::Reg(nr.clone(), nl));
-                            }
-                        }
-                    },
-                    Some(&Mnemonic::Jp) => {
-                        match last_token {
-                            None => {
-                                if nr.number() != 0 {
-                                    return Err(Token::Reg(nr.clone(), nl));
-                                }
-                                temp_last_token = Some(TokenRef::Reg(nr, nl));
-                            }
-                            _ => {
-                                return Err(Token::Reg(nr.clone(), nl));
-                            }
-                        }
-                    },
-                    Some(&Mnemonic::Call) => {
-                        return Err(Token::Reg(nr.clone(), nl));
-                    },
-                    Some(&Mnemonic::Sys) => {
-                        return Err(Token::Reg(nr.clone(), nl));
-                    },
-                    None => {
-                        return Err(Token::Reg(nr.clone(), nl));
+                    _ => token.clone(),
+                }));
+            },
+            _ => match code.first().filter(|t| t.kind == TokenKind::Ident).map(|t| t.name()) {
+                Some(name) if self.macros.contains_key(name) => {
+                    let definition = Rc::clone(&self.macros[name]);
+                    self.output.extend(labels(line));
+                    self.expand(name, &definition, &code, depth);
+                },
+                _ => {
+                    if code.iter().any(|t| matches!(t.kind, TokenKind::Opcode(Mnemonic::Equ | Mnemonic::Const))) {
+                        self.define_constants(line);
                     }
+                    self.output.extend(line.iter().cloned());
+                },
+            },
+        }
+    }
+
+    // Preprocesses the lines of the file named on an `INCLUDE` line in its
+    // place
+    fn include(&mut self, code: &[&Token], depth: usize) {
+        match include_file(code, self.include_dirs, self.files, &self.open, self.errors) {
+            Ok((tokens, canonical)) => {
+                self.open.push(canonical);
+                for line in lines(&tokens) {
+                    self.line(line, depth);
                 }
-                last_token = temp_last_token;
+                self.open.pop();
             },
-            &Token::Opcode(ref no, nl) => {
-                match curr_opcode {
+            Err(err) => self.errors.push(err),
+        }
+    }
+
+    // Whether the current line is assembled
+    fn active(&self) -> bool {
+        self.conditions.last().is_none_or(|condition| condition.active)
+    }
+
+    // Reports the blocks left open and returns the resulting tokens
+    fn finish(mut self) -> Vec<Token> {
+        if let Some(block) = self.block.take() {
+            let mnemonic = block.mnemonic();
+            self.errors.push(AssembleError::UnclosedBlock { mnemonic, span: block.code[0].span });
+            if mnemonic == Mnemonic::Macro {
+                self.define_macro(block);
+            }
+        }
+        for condition in self.conditions.drain(..) {
+            self.errors.push(AssembleError::UnclosedBlock { mnemonic: condition.mnemonic, span: condition.span });
+        }
+        self.output
+    }
+
+    fn define_macro(&mut self, block: Block) {
+        let code: Vec<&Token> = block.code.iter().collect();
+        match read_macro(&code, block.body) {
+            Ok((name, _)) if self.macros.contains_key(&name) => {
+                let previous = self.macros[&name].span;
+                let span = code[1].span;
+                self.errors.push(AssembleError::DuplicateSymbol { name, span, previous: Some(previous) });
+            },
+            Ok((name, definition)) => {
+                self.macros.insert(name, Rc::new(definition));
+            },
+            Err(err) => self.errors.push(err),
+        }
+    }
+
+    // Records the constants a line defines, so that later conditions can
+    // use them. Errors are left for the parser to report.
+    fn define_constants(&mut self, line: &[Token]) {
+        let mut statements = vec![];
+        let _ = parse_line(line, &mut statements);
+        for statement in statements {
+            if let StatementKind::Constant(name, value) = statement.kind {
+                self.symbols.insert(name, Symbol::Constant(value, statement.span, Cell::new(None)));
+            }
+        }
+    }
+
+    // Opens, continues or closes a conditional block
+    fn condition(&mut self, mnemonic: Mnemonic, code: &[&Token]) {
+        let span = code[0].span;
+        match mnemonic {
+            Mnemonic::If | Mnemonic::IfDef | Mnemonic::IfNDef => {
+                let enclosing = self.active();
+                // Conditions in skipped blocks may refer to anything
+                let active = enclosing && self.holds(mnemonic, code);
+                self.conditions.push(Condition { mnemonic, span, active, done: !enclosing || active, in_else: false });
+            },
+            Mnemonic::ElseIf | Mnemonic::Else => {
+                let (done, in_else) = match self.conditions.last() {
+                    Some(condition) => (condition.done, condition.in_else),
                     None => {
-                        curr_opcode = Some(no);
-                    },
-                    _ => {
-                        return Err(Token::Opcode(no.clone(), nl));
+                        self.errors.push(AssembleError::UnmatchedBlockEnd { mnemonic, span });
+                        return;
                     }
+                };
+                if in_else {
+                    self.errors.push(AssembleError::BranchAfterElse { mnemonic, span });
                 }
+                let active = !done && (mnemonic == Mnemonic::Else || self.holds(mnemonic, code));
+                let condition = self.conditions.last_mut().unwrap();
+                condition.active = active;
+                condition.done = done || active;
+                condition.in_else = in_else || mnemonic == Mnemonic::Else;
             },
-            &Token::ImmConst(ref nc, nl) => {
-                match curr_opcode {
-                    Some(&Mnemonic::Ld) => {
-                        match last_token {
-                            Some(TokenRef::Reg(ref or, _)) => {
-                                result.push((0x6u16 << 12) | ((or.number() as u16) << 8) | (nc & 0x00ff));
-                                temp_last_token = None;
-                                curr_opcode = None;
-                            },
-                            Some(TokenRef::I(_)) => {
-                                result.push((0xau16 << 12) | (nc & 0x0fff));
-                                temp_last_token = None;
-                                curr_opcode = None;
-                            },
-                            _ => {
-                                return Err(Token::ImmConst(*nc, nl));
-                            }
-                        }
-                    },
-                    Some(&Mnemonic::Se) => {
-                        match last_token {
-                            Some(TokenRef::Reg(ref or, _)) => {
-                                result.push((0x3u16 << 12) | ((or.number() as u16) << 8) | (nc & 0x00ff));
-                                temp_last_token = None;
-                                curr_opcode = None;
-                            },
-                            _ => {
-                                return Err(Token::ImmConst(*nc, nl));
-                            }
-                        }
-                    },
-                    Some(&Mnemonic::Sne) => {
-                        match last_token {
-                            Some(TokenRef::Reg(ref or, _)) => {
-                                result.push((0x4u16 << 12) | ((or.number() as u16) << 8) | (nc & 0x00ff));
-                                temp_last_token = None;
-                                curr_opcode = None;
-                            },
-                            _ => {
-                                return Err(Token::ImmConst(*nc, nl));
-                            }
-                        }
-                    },
-                    Some(&Mnemonic::Add) => {
-                        match last_token {
-                            Some(TokenRef::Reg(ref or, _)) => {
-                                result.push((0x7u16 << 12) | ((or.number() as u16) << 8) | (nc & 0x00ff));
-                                temp_last_token = None;
-                                curr_opcode = None;
-                            },
-                            _ => {
-                                return Err(Token::ImmConst(*nc, nl));
-                            }
-                        }
-                    },
-                    Some(&Mnemonic::Rnd) => {
-                        match last_token {
-                            Some(TokenRef::Reg(ref or, _)) => {
-                                result.push((0xcu16 << 12) | ((or.number() as u16) << 8) | (nc & 0x00ff));
-                                temp_last_token = None;
-                                curr_opcode = None;
-                            },
-                            _ => {
-                                return Err(Token::ImmConst(*nc, nl));
-                            }
-                        }
-                    },
-                    Some(&Mnemonic::Drw) => {
-                        match last_token {
-                            Some(TokenRef::Reg(ref or, _)) => {
-                                if draw_first_reg.is_none() {
-                                    return Err(Token::ImmConst(*nc, nl));
-                                }
-                                let x = draw_first_reg.unwrap().0.number();
-                                let y = or.number();
-                                result.push((0xdu16 << 12) | (((x & 0x0f) as u16) << 8) | (((y & 0x0f) as u16) << 4) | (nc & 0x000f));
-                                temp_last_token = None;
-                                draw_first_reg = None;
-                                curr_opcode = None;
-                            },
-                            _ => {
-                                return Err(Token::ImmConst(*nc, nl));
-                            }
-                        }
-                    },
-                    Some(&Mnemonic::Jp) => {
-                        match last_token {
-                            Some(TokenRef::Reg(ref or, _)) => {
-                                if or.number() != 0 {
-                                    return Err(Token::ImmConst(*nc, nl));
-                                }
-                                result.push((0xbu16 << 12) | (nc & 0x0fff));
-                                temp_last_token = None;
-                                curr_opcode = None;
-                            },
-                            None => {
-                                result.push((0x1u16 << 12) | (nc & 0x0fff));
-                                temp_last_token = None;
-                                curr_opcode = None;
-                            },
-                            _ => {
-                                return Err(Token::ImmConst(*nc, nl));
-                            }
-                        }
-                    },
-                    Some(&Mnemonic::Call) => {
-                        if last_token.is_some() {
-                            return Err(Token::ImmConst(*nc, nl));
-                        }
-                        result.push((0x2u16 << 12) | (nc & 0x0fff));
-                        temp_last_token = None;
-                        curr_opcode = None;
-                    },
-                    _ => {
-                        return Err(Token::ImmConst(*nc, nl));
-                    }
+            _ => {
+                if self.conditions.pop().is_none() {
+                    self.errors.push(AssembleError::UnmatchedBlockEnd { mnemonic, span });
                 }
-                last_token = temp_last_token;
-            },
-            &Token::K(nl) => {
-                match curr_opcode {
-                    Some(&Mnemonic::Ld) => {
-                        match last_token {
-                            Some(TokenRef::Reg(ref or, _)) => {
-                                result.push((0xfu16 << 12) | (((or.number() as u16) & 0x000f) << 8) | 0x0a);
-                                temp_last_token = None;
-                                curr_opcode = None;
-                            },
-                            _ => {
-                                return Err(Token::K(nl));
-                            }
-                        }
-                    },
-                    _ => {
-                        return Err(Token::K(nl));
-                    }
+            },
+        }
+    }
+
+    // Whether the condition of an `IF`, `ELSEIF`, `IFDEF` or `IFNDEF` line
+    // holds. A condition with an error does not.
+    fn holds(&mut self, mnemonic: Mnemonic, code: &[&Token]) -> bool {
+        let mut cursor = Cursor { tokens: &code[1..], pos: 0 };
+        let result = cursor.operands().and_then(|operands| match (mnemonic, &operands.args[..]) {
+            (Mnemonic::IfDef, &[Arg::Imm(Expr { kind: ExprKind::Symbol(ref name), .. })]) => {
+                Ok(self.symbols.contains_key(name))
+            },
+            (Mnemonic::IfNDef, &[Arg::Imm(Expr { kind: ExprKind::Symbol(ref name), .. })]) => {
+                Ok(!self.symbols.contains_key(name))
+            },
+            (Mnemonic::If | Mnemonic::ElseIf, &[Arg::Imm(ref condition)]) => {
+                resolve(condition, &self.symbols).map(|value| value != 0)
+            },
+            _ => Err(operand_error(mnemonic, code[0].span, &operands)),
+        });
+        result.unwrap_or_else(|err| {
+            self.errors.push(err);
+            false
+        })
+    }
+
+    // Appends the body of a macro in place of the line `code` calling it,
+    // preprocessing its lines in turn
+    fn expand(&mut self, name: &str, definition: &Macro, code: &[&Token], depth: usize) {
+        let call = code[0];
+        if !self.may_expand(name, call.span, depth) {
+            return;
+        }
+
+        // Arguments are separated by the commas outside parentheses
+        let mut args: Vec<Vec<&Token>> = vec![];
+        let mut nesting = 0;
+        for token in &code[1..] {
+            match token.kind {
+                TokenKind::Comma if nesting == 0 => {
+                    args.push(vec![]);
+                    continue;
+                },
+                TokenKind::LParen => nesting += 1,
+                TokenKind::RParen => nesting -= 1,
+                _ => {},
+            }
+            match args.last_mut() {
+                Some(arg) => arg.push(token),
+                None => args.push(vec![token]),
+            }
+        }
+        if args.len() != definition.params.len() {
+            self.errors.push(AssembleError::MacroArguments {
+                name: name.to_owned(), expected: definition.params.len(), found: args.len(), span: call.span,
+            });
+            return;
+        }
+
+        self.expansions += 1;
+        let expansion = self.files.add_expansion(name, call.span);
+        let body = instantiate(&definition.body, expansion, |_, ident| {
+            let i = definition.params.iter().position(|p| p == ident)?;
+            Some(args[i].iter().map(|&arg| arg.clone()).collect())
+        });
+        self.expanded += body.len();
+        for line in lines(&body) {
+            self.line(line, depth + 1);
+        }
+    }
+
+    // Whether a macro or repeated block used at `span` can be expanded. Once
+    // expansions are nested too deeply or there are too many of them, the
+    // expansion the line is in stops, so that a macro using itself more than
+    // once is reported only once.
+    fn may_expand(&mut self, name: &str, span: Span, depth: usize) -> bool {
+        let err = if depth >= MAX_MACRO_DEPTH {
+            AssembleError::MacroRecursion { name: name.to_owned(), span }
+        } else if self.exhausted() {
+            AssembleError::TooManyExpansions { span }
+        } else {
+            return true;
+        };
+        self.errors.push(err);
+        self.halted = true;
+        false
+    }
+
+    // Whether expansions have been made or have produced tokens as many
+    // times as a program can have
+    fn exhausted(&self) -> bool {
+        self.expansions >= MAX_EXPANSIONS || self.expanded >= MAX_EXPANDED_TOKENS
+    }
+
+    // Expands a `REPT` or `FOR` block once for every repetition
+    fn repeat(&mut self, block: Block, depth: usize) {
+        let mnemonic = block.mnemonic();
+        let code: Vec<&Token> = block.code.iter().collect();
+        if !self.may_expand(&mnemonic.to_string(), code[0].span, depth) {
+            return;
+        }
+        // The counter of a `FOR` can have the name of a keyword, e.g. `i`
+        let counter_name = code.get(1).map(|token| match token.kind {
+            TokenKind::F | TokenKind::B | TokenKind::K | TokenKind::I | TokenKind::St | TokenKind::Dt
+                if mnemonic == Mnemonic::For => {
+                Token { kind: TokenKind::Ident, ..(*token).clone() }
+            },
+            _ => (*token).clone(),
+        });
+        let operand_tokens: Vec<&Token> = counter_name.iter().chain(code.iter().skip(2).cloned()).collect();
+        let mut cursor = Cursor { tokens: &operand_tokens, pos: 0 };
+        let result = cursor.operands().and_then(|operands| match (mnemonic, &operands.args[..]) {
+            (Mnemonic::Rept, &[Arg::Imm(ref count)]) => {
+                let count = resolve(count, &self.symbols).and_then(|n| within(n, 0, MAX_REPETITIONS, count.span))?;
+                Ok((None, 0..=count - 1))
+            },
+            (Mnemonic::For, &[Arg::Imm(Expr { kind: ExprKind::Symbol(ref name), .. }), Arg::Assign,
+                              Arg::Imm(ref first), Arg::Imm(Expr { kind: ExprKind::Symbol(ref to), .. }),
+                              Arg::Imm(ref last)]) if to.eq_ignore_ascii_case("to") => {
+                let first = resolve(first, &self.symbols)?;
+                let last = resolve(last, &self.symbols).and_then(|n| {
+                    within(n, first.saturating_sub(1), first.saturating_add(MAX_REPETITIONS - 1), last.span)
+                })?;
+                Ok((Some(name.clone()), first..=last))
+            },
+            _ => Err(operand_error(mnemonic, code[0].span, &operands)),
+        });
+        let (counter, values) = match result {
+            Ok(repetitions) => repetitions,
+            Err(err) => {
+                self.errors.push(err);
+                return;
+            }
+        };
+
+        for value in values {
+            if self.halted {
+                break;
+            }
+            if self.exhausted() {
+                self.errors.push(AssembleError::TooManyExpansions { span: code[0].span });
+                self.halted = true;
+                break;
+            }
+            self.expansions += 1;
+            let expansion = self.files.add_expansion(&mnemonic.to_string(), code[0].span);
+            let body = instantiate(&block.body, expansion, |token, ident| {
+                // A counter named after a keyword is matched however it is
+                // capitalized, as keywords are
+                let matches = match token.kind {
+                    TokenKind::Ident => counter.as_deref() == Some(ident),
+                    _ => counter.as_deref().is_some_and(|counter| counter.eq_ignore_ascii_case(ident)),
+                };
+                if matches {
+                    Some(vec![Token { kind: TokenKind::ImmConst(value), ..token.clone() }])
+                } else {
+                    None
                 }
-                last_token = temp_last_token;
+            });
+            self.expanded += body.len();
+            for line in lines(&body) {
+                self.line(line, depth + 1);
+            }
+        }
+    }
+}
+
+// Copies the body of a macro or repeated block for one expansion. Labels it
+// defines are made local to the expansion and identifiers for which
+// `substitute` gives tokens are replaced by them, located at the identifier.
+// Keywords that can name a `FOR` counter, e.g. `i`, are given to it with
+// their text.
+fn instantiate<F>(body: &[Token], expansion: usize, substitute: F) -> Vec<Token>
+    where F: Fn(&Token, &str) -> Option<Vec<Token>>
+{
+    let local = |label: &str| format!("{}@{}", label, expansion);
+    let labels: Vec<&str> = body.iter()
+        .filter(|t| t.kind == TokenKind::Label && !is_anonymous(t.name()))
+        .map(Token::name)
+        .collect();
+    let mut copy = Vec::with_capacity(body.len());
+    for token in body {
+        let span = Span { expansion: Some(expansion), ..token.span };
+        match token.kind {
+            TokenKind::Ident | TokenKind::F | TokenKind::B | TokenKind::K |
+            TokenKind::I | TokenKind::St | TokenKind::Dt => match substitute(token, token.name()) {
+                Some(tokens) => copy.extend(tokens.into_iter().map(|token| Token { span, ..token })),
+                None if token.kind == TokenKind::Ident && labels.contains(&token.name()) => {
+                    copy.push(Token::with_text(TokenKind::Ident, span, local(token.name())));
+                },
+                None => copy.push(Token { span, ..token.clone() }),
             },
-            &Token::F(nl) => {
-                match curr_opcode {
-                    Some(&Mnemonic::Ld) => {
-                        if last_token.is_some() {
-                            return Err(Token::F(nl));
-                        }
-                        temp_last_token = Some(TokenRef::F(nl));
-                    },
-                    _ => {
-                        return Err(Token::F(nl));
-                    }
+            TokenKind::Label if !is_anonymous(token.name()) => {
+                copy.push(Token::with_text(TokenKind::Label, span, local(token.name())));
+            },
+            _ => copy.push(Token { span, ..token.clone() }),
+        }
+    }
+    copy
+}
+
+// The tokens of a line besides its labels and comment
+fn code_tokens(line: &[Token]) -> Vec<&Token> {
+    line.iter().filter(|t| !matches!(t.kind, TokenKind::Label | TokenKind::Comment)).collect()
+}
+
+// Anonymous labels, `+:` and `-:`, are told apart by their position alone
+fn is_anonymous(label: &str) -> bool {
+    label == "+" || label == "-"
+}
+
+// The label definitions of a line
+fn labels(line: &[Token]) -> impl Iterator<Item = Token> + '_ {
+    line.iter().filter(|t| t.kind == TokenKind::Label).cloned()
+}
+
+// Reads the name and parameters on a `MACRO` line
+fn read_macro(code: &[&Token], body: Vec<Token>) -> Result<(String, Macro), AssembleError> {
+    let (name, span) = match code.get(1) {
+        Some(token) if token.kind == TokenKind::Ident => (token.name().to_owned(), token.span),
+        Some(token) => {
+            return Err(AssembleError::WrongOperand {
+                mnemonic: Mnemonic::Macro, found: token.to_string(), span: token.span,
+            });
+        },
+        None => return Err(AssembleError::MissingOperand { mnemonic: Mnemonic::Macro, span: code[0].span }),
+    };
+    let mut params = vec![];
+    for token in code[2..].iter().filter(|t| !matches!(t.kind, TokenKind::Comma)) {
+        match token.kind {
+            TokenKind::Ident => params.push(token.name().to_owned()),
+            _ => {
+                return Err(AssembleError::WrongOperand {
+                    mnemonic: Mnemonic::Macro, found: token.to_string(), span: token.span,
+                });
+            }
+        }
+    }
+    Ok((name, Macro { params, body, span }))
+}
+
+// Removes the tokens of the given line from the end of `tokens`, except for
+// label definitions which stay valid regardless of the rest of the line
+fn discard_line(tokens: &mut Vec<Token>, line: u32) {
+    let start = tokens.iter().rposition(|t| t.span.line != line).map_or(0, |p| p + 1);
+    let tail = tokens.split_off(start);
+    tokens.extend(tail.into_iter().filter(|t| t.kind == TokenKind::Label));
+}
+
+
+#[derive(Debug, Clone)]
+pub enum StatementKind {
+    /// A label definition, e.g. `loop:`
+    Label(String),
+    Instruction(Instruction<Expr>),
+    /// Bytes emitted by `DB`
+    Bytes(Vec<Expr>),
+    /// Big-endian words emitted by `DW`
+    Words(Vec<Expr>),
+    /// Moves the location counter to the given address
+    Org(Expr),
+    /// Moves the location counter to the next multiple of the given number
+    Align(Expr),
+    /// A named constant defined by `EQU` or `.const`
+    Constant(String, Expr),
+    /// A sprite cut out of an image file by `INCSPRITE`
+    IncSprite { path: String, x: Expr, y: Expr, width: Expr, height: Expr },
+    /// Bytes of a file embedded by `INCBIN`, by default all of them
+    IncBin { path: String, offset: Option<Expr>, length: Option<Expr> },
+    /// A string emitted by `.text`, as bytes, or `.textw`, as words, each
+    /// character replaced by its value in the character map
+    Text(String, Width),
+    /// Maps a character to the value `.text` emits for it, from this
+    /// statement on
+    CharMap(Expr, Expr),
+}
+
+/// A label or an instruction along with its location in the source
+#[derive(Debug, Clone)]
+pub struct Statement {
+    pub kind: StatementKind,
+    pub span: Span,
+}
+
+impl Statement {
+    pub fn new(kind: StatementKind, span: Span) -> Statement {
+        Statement { kind, span }
+    }
+
+    /// Number of bytes the statement emits. `ORG` and `ALIGN` emit padding
+    /// that depends on where they are, `INCSPRITE` and `INCBIN` the contents
+    /// of a file, which are not included.
+    pub fn size(&self) -> u16 {
+        match self.kind {
+            StatementKind::Label(_) | StatementKind::Org(_) |
+            StatementKind::Align(_) | StatementKind::Constant(..) |
+            StatementKind::IncSprite { .. } | StatementKind::IncBin { .. } |
+            StatementKind::CharMap(..) => 0,
+            StatementKind::Instruction(_) => INSTRUCTION_SIZE,
+            StatementKind::Bytes(ref values) => values.len() as u16,
+            StatementKind::Words(ref values) => 2 * values.len() as u16,
+            StatementKind::Text(ref text, Width::Word) => 2 * text.chars().count() as u16,
+            StatementKind::Text(ref text, _) => text.chars().count() as u16,
+        }
+    }
+
+    // Calls `f` with every symbol reference in the operands of the statement
+    fn visit_symbols<F>(&mut self, f: &mut F)
+        where F: FnMut(&mut Expr)
+    {
+        let exprs: Vec<&mut Expr> = match self.kind {
+            StatementKind::Label(_) | StatementKind::Text(..) => vec![],
+            StatementKind::Instruction(ref mut instruction) => {
+                let visited = instruction.clone().map(|mut op, _| -> Result<Expr, ()> {
+                    op.visit_symbols(f);
+                    Ok(op)
+                });
+                if let Ok(visited) = visited {
+                    *instruction = visited;
                 }
-                last_token = temp_last_token;
+                return;
             },
-            &Token::B(nl) => {
-                match curr_opcode {
-                    Some(&Mnemonic::Ld) => {
-                        if last_token.is_some() {
-                            return Err(Token::B(nl));
-                        }
-                        temp_last_token = Some(TokenRef::B(nl));
+            StatementKind::Bytes(ref mut values) | StatementKind::Words(ref mut values) => values.iter_mut().collect(),
+            StatementKind::Org(ref mut op) | StatementKind::Align(ref mut op) |
+            StatementKind::Constant(_, ref mut op) => vec![op],
+            StatementKind::CharMap(ref mut c, ref mut value) => vec![c, value],
+            StatementKind::IncSprite { ref mut x, ref mut y, ref mut width, ref mut height, .. } => {
+                vec![x, y, width, height]
+            },
+            StatementKind::IncBin { ref mut offset, ref mut length, .. } => {
+                offset.iter_mut().chain(length.iter_mut()).collect()
+            },
+        };
+        for expr in exprs {
+            expr.visit_symbols(f);
+        }
+    }
+}
+
+/// Gives local and anonymous labels names of their own. A local label such
+/// as `.loop` belongs to the last global label defined before it, `main`,
+/// and is renamed `main.loop`, the name references from other scopes use.
+/// An anonymous label `-:` is referred to as `-` from the lines after it,
+/// up to the next one, or as `--` from after the next one and so on; `+:`
+/// labels are referred to the same way from the lines before them. Labels
+/// local to a macro or repetition do not start a scope.
+pub fn qualify_labels(statements: &mut [Statement], errors: &mut Errors) {
+    // Global label of every statement, the local labels defined under each
+    // name and the anonymous labels with the index of their statement
+    let mut scopes: Vec<Option<String>> = Vec::with_capacity(statements.len());
+    let mut locals: HashMap<String, Vec<String>> = HashMap::new();
+    let mut anonymous: Vec<(usize, String)> = vec![];
+    let mut scope: Option<String> = None;
+    for (i, statement) in statements.iter_mut().enumerate() {
+        if let StatementKind::Label(ref mut name) = statement.kind {
+            if is_anonymous(name) {
+                anonymous.push((i, name.clone()));
+                *name = format!("{}{}", name, i);
+            } else if name.starts_with('.') {
+                let qualified = qualify(scope.as_deref(), name);
+                locals.entry(name.clone()).or_default().push(qualified.clone());
+                *name = qualified;
+            } else if !name.contains('@') {
+                scope = Some(name.clone());
+            }
+        }
+        scopes.push(scope.clone());
+    }
+
+    for (i, (statement, scope)) in statements.iter_mut().zip(scopes).enumerate() {
+        statement.visit_symbols(&mut |expr| {
+            let name = match expr.kind {
+                ExprKind::Symbol(ref name) => name.clone(),
+                _ => return,
+            };
+            let resolved = if name.starts_with(['+', '-']) {
+                let kind = &name[..1];
+                // Labels in the direction of the reference, nearest first
+                let labels: Vec<usize> = if kind == "+" {
+                    anonymous.iter().filter(|&&(j, ref k)| k == kind && j > i).map(|&(j, _)| j).collect()
+                } else {
+                    anonymous.iter().rev().filter(|&&(j, ref k)| k == kind && j < i).map(|&(j, _)| j).collect()
+                };
+                match labels.get(name.len() - 1) {
+                    Some(j) => Ok(format!("{}{}", kind, j)),
+                    None => {
+                        let found = labels.len();
+                        Err(AssembleError::MissingAnonymousLabel { reference: name, found, span: expr.span })
                     },
-                    _ => {
-                        return Err(Token::B(nl));
-                    }
                 }
-                last_token = temp_last_token;
+            } else if name.starts_with('.') {
+                let qualified = qualify(scope.as_deref(), &name);
+                let others = locals.get(&name).cloned().unwrap_or_default();
+                if others.contains(&qualified) {
+                    Ok(qualified)
+                } else {
+                    Err(AssembleError::UndefinedLocalLabel { name, scope: scope.clone(), others, span: expr.span })
+                }
+            } else {
+                return;
+            };
+            match resolved {
+                Ok(resolved) => expr.kind = ExprKind::Symbol(resolved),
+                // Reported here rather than as an undefined symbol
+                Err(err) => {
+                    errors.push(err);
+                    expr.kind = ExprKind::Number(0);
+                },
+            }
+        });
+    }
+}
+
+// Name of the local label `.name` under the global label `scope`
+fn qualify(scope: Option<&str>, name: &str) -> String {
+    format!("{}{}", scope.unwrap_or_default(), name)
+}
+
+// An operand reduced to what matters when choosing an instruction form
+#[derive(Debug, Clone)]
+enum Arg {
+    Reg(Register),
+    Imm(Expr),
+    F, B, K,
+    I, St, Dt,
+    IVal, Assign,
+    Str(String),
+    // A token that can never be an operand, e.g. a mnemonic
+    Other(String),
+}
+
+impl Arg {
+    fn fits(&self, expect: Expect) -> bool {
+        matches!((self, expect),
+            (&Arg::Reg(Register::V0), Expect::V0) |
+            (&Arg::Reg(_), Expect::Reg) |
+            (&Arg::Imm(_), Expect::Imm) |
+            (&Arg::Imm(Expr { kind: ExprKind::Symbol(_), .. }), Expect::Symbol) |
+            (&Arg::Assign, Expect::Assign) |
+            (&Arg::F, Expect::F) | (&Arg::B, Expect::B) | (&Arg::K, Expect::K) |
+            (&Arg::I, Expect::I) | (&Arg::St, Expect::St) | (&Arg::Dt, Expect::Dt) |
+            (&Arg::IVal, Expect::IVal) | (&Arg::Str(_), Expect::Str))
+    }
+}
+
+impl fmt::Display for Arg {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Arg::Reg(ref r) => write!(f, "{}", r),
+            Arg::Imm(ref expr) => write!(f, "{}", expr),
+            Arg::F => write!(f, "F"),
+            Arg::B => write!(f, "B"),
+            Arg::K => write!(f, "K"),
+            Arg::I => write!(f, "I"),
+            Arg::St => write!(f, "ST"),
+            Arg::Dt => write!(f, "DT"),
+            Arg::IVal => write!(f, "[I]"),
+            Arg::Assign => write!(f, "="),
+            Arg::Str(ref text) => write!(f, "{:?}", text),
+            Arg::Other(ref text) => write!(f, "{}", text),
+        }
+    }
+}
+
+// The operands of a line along with their locations
+#[derive(Default)]
+struct Operands {
+    args: Vec<Arg>,
+    spans: Vec<Span>,
+}
+
+// Operand expected at some position of an instruction form
+#[derive(Debug, Copy, Clone)]
+enum Expect {
+    Reg, V0, Imm,
+    F, B, K,
+    I, St, Dt,
+    IVal, Symbol, Assign,
+    Str,
+}
+
+// Operand forms accepted by each mnemonic, used to tell which operand of a
+// line is at fault when it matches none of them
+fn forms(mnemonic: Mnemonic) -> &'static [&'static [Expect]] {
+    use self::Expect::*;
+    match mnemonic {
+        Mnemonic::Add => &[&[Reg, Reg], &[Reg, Imm], &[I, Reg]],
+        Mnemonic::And | Mnemonic::Or | Mnemonic::Xor |
+        Mnemonic::Sub | Mnemonic::Subn => &[&[Reg, Reg]],
+        Mnemonic::Call | Mnemonic::Sys => &[&[Imm]],
+        Mnemonic::Cls | Mnemonic::Ret => &[&[]],
+        Mnemonic::Drw => &[&[Reg, Reg, Imm]],
+        Mnemonic::Jp => &[&[Imm], &[V0, Imm]],
+        Mnemonic::Ld => &[&[Reg, Imm], &[Reg, Reg], &[I, Imm], &[Reg, Dt], &[Reg, K], &[Dt, Reg],
+                          &[St, Reg], &[F, Reg], &[B, Reg], &[IVal, Reg], &[Reg, IVal]],
+        Mnemonic::Rnd => &[&[Reg, Imm]],
+        Mnemonic::Se | Mnemonic::Sne => &[&[Reg, Imm], &[Reg, Reg]],
+        Mnemonic::Shl | Mnemonic::Shr => &[&[Reg], &[Reg, Reg]],
+        Mnemonic::Skp | Mnemonic::Sknp => &[&[Reg]],
+        Mnemonic::Db | Mnemonic::Dw | Mnemonic::Org |
+        Mnemonic::Align | Mnemonic::Equ => &[&[Imm]],
+        Mnemonic::Const => &[&[Symbol, Assign, Imm]],
+        Mnemonic::Sprite => &[&[Symbol]],
+        Mnemonic::IncSprite => &[&[Str, Imm, Imm, Imm, Imm]],
+        Mnemonic::Include => &[&[Str]],
+        Mnemonic::IncBin => &[&[Str, Imm, Imm]],
+        Mnemonic::Macro => &[&[Symbol]],
+        Mnemonic::Endm | Mnemonic::Else | Mnemonic::EndIf | Mnemonic::Endr => &[&[]],
+        Mnemonic::Rept => &[&[Imm]],
+        Mnemonic::For => &[&[Symbol, Assign, Imm, Symbol, Imm]],
+        Mnemonic::If | Mnemonic::ElseIf => &[&[Imm]],
+        Mnemonic::IfDef | Mnemonic::IfNDef => &[&[Symbol]],
+        Mnemonic::Text | Mnemonic::TextW => &[&[Str]],
+        Mnemonic::CharMap => &[&[Imm, Imm]],
+    }
+}
+
+/// Groups the tokens into lines and parses each line into its label
+/// definitions followed by at most one instruction. A line with an error
+/// keeps its labels, the rest of it is dropped and the error is recorded.
+pub fn parse(tokens: &[Token], errors: &mut Errors) -> Vec<Statement> {
+    let mut statements: Vec<Statement> = vec![];
+    let mut lines = lines(tokens).into_iter().peekable();
+    while let Some(line) = lines.next() {
+        let result = parse_line(line, &mut statements).and_then(|header| match header {
+            Some(header) => {
+                let mut rows = vec![];
+                while let Some(row) = lines.peek().and_then(|line| sprite_row(line)) {
+                    rows.push(row);
+                    lines.next();
+                }
+                sprite(header, &rows, &mut statements)
             },
-            &Token::I(nl) => {
-                match curr_opcode {
-                    Some(&Mnemonic::Ld) | Some(&Mnemonic::Add) => {
-                        if last_token.is_some() {
-                            return Err(Token::I(nl));
-                        }
-                        temp_last_token = Some(TokenRef::I(nl));
-                    },
-                    _ => {
-                        return Err(Token::I(nl));
-                    }
+            None => Ok(()),
+        });
+        if let Err(err) = result {
+            errors.push(err);
+        }
+    }
+    statements
+}
+
+// Splits the tokens into lines, each from a single file or expansion
+fn lines(tokens: &[Token]) -> Vec<&[Token]> {
+    let mut lines = vec![];
+    let mut rest = tokens;
+    while let Some(first) = rest.first() {
+        let len = rest.iter().position(|t| {
+            (t.span.file, t.span.line, t.span.expansion) != (first.span.file, first.span.line, first.span.expansion)
+        }).unwrap_or(rest.len());
+        let (line, next) = rest.split_at(len);
+        lines.push(line);
+        rest = next;
+    }
+    lines
+}
+
+// A `.sprite` line, whose rows are on the lines that follow it
+struct SpriteHeader {
+    name: String,
+    span: Span,
+    opcode_span: Span,
+}
+
+// The row of pixels on a line, if the line holds nothing else
+fn sprite_row(line: &[Token]) -> Option<(&str, Span)> {
+    let mut tokens = line.iter().filter(|t| !matches!(t.kind, TokenKind::Comment));
+    let token = tokens.next()?;
+    let row = match token.kind {
+        TokenKind::Pixels => token.name(),
+        TokenKind::Ident if token.name().chars().all(Stream::is_pixel) => token.name(),
+        _ => return None,
+    };
+    if tokens.next().is_some() {
+        return None;
+    }
+    Some((row, line[0].span))
+}
+
+// Turns the rows of a sprite into bytes along with a label for its address
+// and a `NAME_height` constant for `DRW`, which is 0 for a 16x16 sprite
+fn sprite(header: SpriteHeader, rows: &[(&str, Span)], statements: &mut Vec<Statement>) -> Result<(), AssembleError> {
+    let (first, last) = match (rows.first(), rows.last()) {
+        (Some(first), Some(last)) => (first.1, last.1),
+        _ => return Err(AssembleError::MissingOperand { mnemonic: Mnemonic::Sprite, span: header.opcode_span }),
+    };
+    let width = first.len();
+    if let Some(&(row, span)) = rows.iter().find(|&&(row, _)| row.len() != width) {
+        return Err(AssembleError::SpriteRowWidth { expected: width, found: row.len(), span });
+    }
+    let span = first.to(last);
+
+    let mut bitmap = Bitmap::new(width, rows.len());
+    for (y, &(row, _)) in rows.iter().enumerate() {
+        for (x, c) in row.chars().enumerate() {
+            bitmap.set(x, y, c != PIXEL_OFF);
+        }
+    }
+    let bytes = bitmap.sprite(0, 0, width, rows.len()).map_err(|_| {
+        AssembleError::SpriteSize { width, height: rows.len(), span }
+    })?;
+    let bytes = bytes.chunks(bytes.len() / rows.len()).zip(rows).flat_map(|(row, &(_, span))| {
+        row.iter().map(move |&byte| Expr::new(ExprKind::Number(byte as i64), span))
+    }).collect();
+    let height = if rows.len() == LARGE_SPRITE_SIZE { 0 } else { rows.len() as i64 };
+
+    let height = Expr::new(ExprKind::Number(height), span);
+    statements.push(Statement::new(StatementKind::Label(header.name.clone()), header.span));
+    statements.push(Statement::new(StatementKind::Constant(format!("{}_height", header.name), height), header.span));
+    statements.push(Statement::new(StatementKind::Bytes(bytes), span));
+    Ok(())
+}
+
+fn parse_line(line: &[Token], statements: &mut Vec<Statement>) -> Result<Option<SpriteHeader>, AssembleError> {
+    let tokens: Vec<&Token> = line.iter().filter(|t| !matches!(t.kind, TokenKind::Comment)).collect();
+    let mut cursor = Cursor { tokens: &tokens, pos: 0 };
+
+    while let Some(token) = cursor.peek() {
+        match token.kind {
+            TokenKind::Label => {
+                statements.push(Statement::new(StatementKind::Label(token.name().to_owned()), token.span));
+                cursor.next();
+            },
+            _ => break,
+        }
+    }
+
+    let opcode = match cursor.next() {
+        Some(token) => token,
+        None => return Ok(None),
+    };
+    let mnemonic = match opcode.kind {
+        TokenKind::Opcode(m) => m,
+        // `NAME EQU value`
+        TokenKind::Ident if cursor.peek().is_some_and(|t| {
+            matches!(t.kind, TokenKind::Opcode(Mnemonic::Equ))
+        }) => {
+            let equ = cursor.next().unwrap();
+            let operands = cursor.operands()?;
+            let kind = match operands.args[..] {
+                [Arg::Imm(ref value)] => StatementKind::Constant(opcode.name().to_owned(), value.clone()),
+                _ => return Err(operand_error(Mnemonic::Equ, equ.span, &operands)),
+            };
+            statements.push(Statement::new(kind, opcode.span));
+            return Ok(None);
+        },
+        _ => {
+            return Err(AssembleError::UnknownMnemonic { name: opcode.to_string(), span: opcode.span });
+        }
+    };
+    let operands = cursor.operands()?;
+    let args = &operands.args;
+
+    let kind = match mnemonic {
+        Mnemonic::Db => StatementKind::Bytes(values(mnemonic, opcode.span, &operands)?),
+        Mnemonic::Dw => StatementKind::Words(values(mnemonic, opcode.span, &operands)?),
+        Mnemonic::Org | Mnemonic::Align => match args[..] {
+            [Arg::Imm(ref value)] if mnemonic == Mnemonic::Org => StatementKind::Org(value.clone()),
+            [Arg::Imm(ref value)] => StatementKind::Align(value.clone()),
+            _ => return Err(operand_error(mnemonic, opcode.span, &operands)),
+        },
+        // The name of the constant comes before `EQU`
+        Mnemonic::Equ => return Err(AssembleError::MissingOperand { mnemonic, span: opcode.span }),
+        Mnemonic::Const => match args[..] {
+            [Arg::Imm(Expr { kind: ExprKind::Symbol(ref name), span }), Arg::Assign, Arg::Imm(ref value)] => {
+                statements.push(Statement::new(StatementKind::Constant(name.clone(), value.clone()), span));
+                return Ok(None);
+            },
+            _ => return Err(operand_error(mnemonic, opcode.span, &operands)),
+        },
+        Mnemonic::Sprite => match args[..] {
+            [Arg::Imm(Expr { kind: ExprKind::Symbol(ref name), span })] => {
+                return Ok(Some(SpriteHeader { name: name.clone(), span, opcode_span: opcode.span }));
+            },
+            _ => return Err(operand_error(mnemonic, opcode.span, &operands)),
+        },
+        Mnemonic::IncSprite => match args[..] {
+            [Arg::Str(ref path), Arg::Imm(ref x), Arg::Imm(ref y), Arg::Imm(ref width), Arg::Imm(ref height)] => {
+                StatementKind::IncSprite {
+                    path: path.clone(),
+                    x: x.clone(),
+                    y: y.clone(),
+                    width: width.clone(),
+                    height: height.clone(),
                 }
-                last_token = temp_last_token;
-            },
-            &Token::IVal(nl) => {
-                match curr_opcode {
-                    Some(&Mnemonic::Ld) => {
-                        match last_token {
-                            Some(TokenRef::Reg(ref or, _)) => {
-                                result.push((0xfu16 << 12) | (((or.number() as u16) & 0x000f) << 8) | 0x65);
-                                temp_last_token = None;
-                                curr_opcode = None;
-                            },
-                            None => {
-                                temp_last_token = Some(TokenRef::IVal(nl));
-                            },
-                            _ => {
-                                return Err(Token::IVal(nl));
-                            }
-                        }
+            },
+            _ => return Err(operand_error(mnemonic, opcode.span, &operands)),
+        },
+        Mnemonic::IncBin => match args[..] {
+            [Arg::Str(ref path)] => StatementKind::IncBin { path: path.clone(), offset: None, length: None },
+            [Arg::Str(ref path), Arg::Imm(ref offset)] => {
+                StatementKind::IncBin { path: path.clone(), offset: Some(offset.clone()), length: None }
+            },
+            [Arg::Str(ref path), Arg::Imm(ref offset), Arg::Imm(ref length)] => {
+                StatementKind::IncBin { path: path.clone(), offset: Some(offset.clone()), length: Some(length.clone()) }
+            },
+            _ => return Err(operand_error(mnemonic, opcode.span, &operands)),
+        },
+        Mnemonic::Text | Mnemonic::TextW => match args[..] {
+            [Arg::Str(ref text)] => {
+                let width = if mnemonic == Mnemonic::Text { Width::Byte } else { Width::Word };
+                StatementKind::Text(text.clone(), width)
+            },
+            _ => return Err(operand_error(mnemonic, opcode.span, &operands)),
+        },
+        Mnemonic::CharMap => match args[..] {
+            [Arg::Imm(ref c), Arg::Imm(ref value)] => StatementKind::CharMap(c.clone(), value.clone()),
+            _ => return Err(operand_error(mnemonic, opcode.span, &operands)),
+        },
+        // Handled by `include` and `preprocess` before lines are parsed
+        Mnemonic::Include | Mnemonic::Macro | Mnemonic::Endm |
+        Mnemonic::If | Mnemonic::ElseIf | Mnemonic::Else | Mnemonic::EndIf |
+        Mnemonic::IfDef | Mnemonic::IfNDef | Mnemonic::Rept | Mnemonic::For |
+        Mnemonic::Endr => return Ok(None),
+        _ => match instruction(mnemonic, args) {
+            Some(instruction) => StatementKind::Instruction(instruction),
+            None => return Err(operand_error(mnemonic, opcode.span, &operands)),
+        },
+    };
+    let mut span = opcode.span;
+    if let Some(&last) = operands.spans.last() {
+        span = span.to(last);
+    }
+    statements.push(Statement::new(kind, span));
+    Ok(None)
+}
+
+// Reads the operands of a line, where an expression may span several tokens
+struct Cursor<'a> {
+    tokens: &'a [&'a Token],
+    pos: usize,
+}
+
+impl<'a> Cursor<'a> {
+    fn peek(&self) -> Option<&'a Token> {
+        self.tokens.get(self.pos).cloned()
+    }
+
+    fn next(&mut self) -> Option<&'a Token> {
+        let token = self.peek();
+        if token.is_some() {
+            self.pos += 1;
+        }
+        token
+    }
+
+    // Reads every remaining operand. Commas between operands are optional.
+    fn operands(&mut self) -> Result<Operands, AssembleError> {
+        let mut operands = Operands::default();
+        while let Some(token) = self.peek() {
+            let arg = match token.kind {
+                TokenKind::Comma => {
+                    self.next();
+                    continue;
+                },
+                TokenKind::ImmConst(_) | TokenKind::Ident | TokenKind::LParen | TokenKind::Op(_) => {
+                    let expr = self.expr(0)?;
+                    operands.spans.push(expr.span);
+                    operands.args.push(Arg::Imm(expr));
+                    continue;
+                },
+                TokenKind::Reg(r) => Arg::Reg(r),
+                TokenKind::F => Arg::F,
+                TokenKind::B => Arg::B,
+                TokenKind::K => Arg::K,
+                TokenKind::I => Arg::I,
+                TokenKind::St => Arg::St,
+                TokenKind::Dt => Arg::Dt,
+                TokenKind::IVal => Arg::IVal,
+                TokenKind::Str => Arg::Str(unescape_str(token.name())),
+                TokenKind::Assign => Arg::Assign,
+                _ => Arg::Other(token.to_string()),
+            };
+            self.next();
+            operands.spans.push(token.span);
+            operands.args.push(arg);
+        }
+        Ok(operands)
+    }
+
+    // Reads an expression whose binary operators bind at least as tightly
+    // as `min`
+    fn expr(&mut self, min: u8) -> Result<Expr, AssembleError> {
+        let mut lhs = self.unary()?;
+        while let Some(&Token { kind: TokenKind::Op(op), .. }) = self.peek() {
+            let precedence = match op.precedence() {
+                Some(p) if p >= min => p,
+                _ => break,
+            };
+            self.next();
+            let rhs = self.expr(precedence + 1)?;
+            let span = lhs.span.to(rhs.span);
+            lhs = Expr::new(ExprKind::Binary(op, Box::new(lhs), Box::new(rhs)), span);
+        }
+        Ok(lhs)
+    }
+
+    fn unary(&mut self) -> Result<Expr, AssembleError> {
+        let token = match self.next() {
+            Some(token) => token,
+            None => return Err(self.expected()),
+        };
+        if let TokenKind::Op(op @ (Operator::Plus | Operator::Minus)) = token.kind {
+            if let Some(reference) = self.anonymous(token, op) {
+                return Ok(reference);
+            }
+        }
+        let kind = match token.kind {
+            TokenKind::ImmConst(n) => ExprKind::Number(n),
+            TokenKind::Op(op) if op.is_unary() => {
+                let operand = self.unary()?;
+                let span = token.span.to(operand.span);
+                return Ok(Expr::new(ExprKind::Unary(op, Box::new(operand)), span));
+            },
+            TokenKind::Ident => match Function::from_name(token.name()) {
+                Some(function) if self.peek().is_some_and(|t| matches!(t.kind, TokenKind::LParen)) => {
+                    let open = self.next().unwrap();
+                    let arg = self.parenthesized(open)?;
+                    let span = token.span.to(self.tokens[self.pos - 1].span);
+                    return Ok(Expr::new(ExprKind::Call(function, Box::new(arg)), span));
+                },
+                _ => ExprKind::Symbol(token.name().to_owned()),
+            },
+            TokenKind::LParen => {
+                let inner = self.parenthesized(token)?;
+                let span = token.span.to(self.tokens[self.pos - 1].span);
+                return Ok(Expr::new(inner.kind, span));
+            },
+            _ => {
+                return Err(AssembleError::ExpectedExpression { found: format!("`{}`", token), span: token.span });
+            }
+        };
+        Ok(Expr::new(kind, token.span))
+    }
+
+    // Reads a reference to an anonymous label, a run of `+` or `-` starting
+    // with `first` that ends an operand, e.g. `JP --`
+    fn anonymous(&mut self, first: &Token, op: Operator) -> Option<Expr> {
+        let mut last = first;
+        let mut len = self.pos;
+        while let Some(&next) = self.tokens.get(len) {
+            match next.kind {
+                TokenKind::Op(o) if o == op && next.span.start == last.span.end => {
+                    last = next;
+                    len += 1;
+                },
+                _ => break,
+            }
+        }
+        match self.tokens.get(len).map(|t| &t.kind) {
+            None | Some(TokenKind::Comma) | Some(TokenKind::RParen) => {
+                let reference = op.to_string().repeat(len - self.pos + 1);
+                self.pos = len;
+                Some(Expr::new(ExprKind::Symbol(reference), first.span.to(last.span)))
+            },
+            _ => None,
+        }
+    }
+
+    // Reads the expression following the opening parenthesis `open` along
+    // with its closing parenthesis
+    fn parenthesized(&mut self, open: &Token) -> Result<Expr, AssembleError> {
+        let inner = self.expr(0)?;
+        match self.next() {
+            Some(&Token { kind: TokenKind::RParen, .. }) => Ok(inner),
+            _ => Err(AssembleError::UnclosedParen { span: open.span }),
+        }
+    }
+
+    // Error for an expression that ends with the line
+    fn expected(&self) -> AssembleError {
+        let last = self.tokens.last();
+        let mut span = last.map_or(Span::default(), |t| t.span);
+        span.column += last.map_or(0, |t| t.text().chars().count()) as u32;
+        span.start = span.end;
+        AssembleError::ExpectedExpression { found: "end of line".to_owned(), span }
+    }
+}
+
+// Collects the operands of a data directive, a non-empty list of values
+fn values(mnemonic: Mnemonic, opcode_span: Span, operands: &Operands) -> Result<Vec<Expr>, AssembleError> {
+    if operands.args.is_empty() {
+        return Err(AssembleError::MissingOperand { mnemonic, span: opcode_span });
+    }
+    let mut values = Vec::with_capacity(operands.args.len());
+    for (arg, &span) in operands.args.iter().zip(&operands.spans) {
+        match *arg {
+            Arg::Imm(ref value) => values.push(value.clone()),
+            _ => return Err(AssembleError::WrongOperand { mnemonic, found: arg.to_string(), span }),
+        }
+    }
+    Ok(values)
+}
+
+// Builds the instruction named by `mnemonic` from its operands, if they
+// form one of the accepted operand forms
+fn instruction(mnemonic: Mnemonic, args: &[Arg]) -> Option<Instruction<Expr>> {
+    use self::Arg::*;
+    Some(match (mnemonic, args) {
+        (Mnemonic::Cls, &[]) => Instruction::Cls,
+        (Mnemonic::Ret, &[]) => Instruction::Ret,
+        (Mnemonic::Sys, &[Imm(ref addr)]) => Instruction::Sys { addr: addr.clone() },
+        (Mnemonic::Jp, &[Imm(ref addr)]) => Instruction::Jp { addr: addr.clone() },
+        (Mnemonic::Jp, &[Reg(Register::V0), Imm(ref addr)]) => Instruction::JpV0 { addr: addr.clone() },
+        (Mnemonic::Call, &[Imm(ref addr)]) => Instruction::Call { addr: addr.clone() },
+        (Mnemonic::Se, &[Reg(x), Imm(ref byte)]) => Instruction::SeByte { x, byte: byte.clone() },
+        (Mnemonic::Se, &[Reg(x), Reg(y)]) => Instruction::SeReg { x, y },
+        (Mnemonic::Sne, &[Reg(x), Imm(ref byte)]) => Instruction::SneByte { x, byte: byte.clone() },
+        (Mnemonic::Sne, &[Reg(x), Reg(y)]) => Instruction::SneReg { x, y },
+        (Mnemonic::Ld, &[Reg(x), Imm(ref byte)]) => Instruction::LdByte { x, byte: byte.clone() },
+        (Mnemonic::Ld, &[Reg(x), Reg(y)]) => Instruction::LdReg { x, y },
+        (Mnemonic::Ld, &[I, Imm(ref addr)]) => Instruction::LdI { addr: addr.clone() },
+        (Mnemonic::Ld, &[Reg(x), Dt]) => Instruction::LdVxDt { x },
+        (Mnemonic::Ld, &[Reg(x), K]) => Instruction::LdVxK { x },
+        (Mnemonic::Ld, &[Dt, Reg(x)]) => Instruction::LdDtVx { x },
+        (Mnemonic::Ld, &[St, Reg(x)]) => Instruction::LdStVx { x },
+        (Mnemonic::Ld, &[F, Reg(x)]) => Instruction::LdFVx { x },
+        (Mnemonic::Ld, &[B, Reg(x)]) => Instruction::LdBVx { x },
+        (Mnemonic::Ld, &[IVal, Reg(x)]) => Instruction::LdIValVx { x },
+        (Mnemonic::Ld, &[Reg(x), IVal]) => Instruction::LdVxIVal { x },
+        (Mnemonic::Add, &[Reg(x), Imm(ref byte)]) => Instruction::AddByte { x, byte: byte.clone() },
+        (Mnemonic::Add, &[Reg(x), Reg(y)]) => Instruction::AddReg { x, y },
+        (Mnemonic::Add, &[I, Reg(x)]) => Instruction::AddIVx { x },
+        (Mnemonic::Or, &[Reg(x), Reg(y)]) => Instruction::Or { x, y },
+        (Mnemonic::And, &[Reg(x), Reg(y)]) => Instruction::And { x, y },
+        (Mnemonic::Xor, &[Reg(x), Reg(y)]) => Instruction::Xor { x, y },
+        (Mnemonic::Sub, &[Reg(x), Reg(y)]) => Instruction::Sub { x, y },
+        (Mnemonic::Subn, &[Reg(x), Reg(y)]) => Instruction::Subn { x, y },
+        (Mnemonic::Shr, &[Reg(x), Reg(y)]) => Instruction::Shr { x, y },
+        (Mnemonic::Shl, &[Reg(x), Reg(y)]) => Instruction::Shl { x, y },
+        // Shifting Vx into itself gives the same result whether the
+        // interpreter shifts Vx or Vy
+        (Mnemonic::Shr, &[Reg(x)]) => Instruction::Shr { x, y: x },
+        (Mnemonic::Shl, &[Reg(x)]) => Instruction::Shl { x, y: x },
+        (Mnemonic::Rnd, &[Reg(x), Imm(ref byte)]) => Instruction::Rnd { x, byte: byte.clone() },
+        (Mnemonic::Drw, &[Reg(x), Reg(y), Imm(ref n)]) => Instruction::Drw { x, y, n: n.clone() },
+        (Mnemonic::Skp, &[Reg(x)]) => Instruction::Skp { x },
+        (Mnemonic::Sknp, &[Reg(x)]) => Instruction::Sknp { x },
+        _ => return None,
+    })
+}
+
+// Finds the operand that keeps a line from matching any form of its
+// mnemonic. The form matching the most leading operands decides whether
+// the line is short of an operand, has one too many or a wrong one.
+fn operand_error(mnemonic: Mnemonic, opcode_span: Span, operands: &Operands) -> AssembleError {
+    let mut matched = 0;
+    let mut complete = false;
+    for form in forms(mnemonic) {
+        let n = form.iter().zip(&operands.args).take_while(|&(e, a)| a.fits(*e)).count();
+        if n > matched {
+            matched = n;
+            complete = false;
+        }
+        if n == matched && n == form.len() {
+            complete = true;
+        }
+    }
+
+    match operands.args.get(matched) {
+        None => AssembleError::MissingOperand { mnemonic, span: opcode_span },
+        Some(arg) if complete => {
+            AssembleError::TrailingOperand { found: arg.to_string(), span: operands.spans[matched] }
+        },
+        Some(arg) => {
+            AssembleError::WrongOperand { mnemonic, found: arg.to_string(), span: operands.spans[matched] }
+        }
+    }
+}
+
+/// A program generated by `code_gen`, along with what listings need to show
+/// where its bytes come from
+#[derive(Debug, Clone)]
+pub struct Assembly {
+    pub code: Vec<u8>,
+    /// What each statement emitted, in the order of the statements.
+    /// Constants, which are not located, are left out.
+    pub map: Vec<Emitted>,
+    /// Value of every label and constant, by name
+    pub symbols: Vec<(String, i64)>,
+}
+
+/// The bytes a statement emitted
+#[derive(Debug, Clone)]
+pub struct Emitted {
+    /// Address of the statement. That of an `ORG` or `ALIGN` is the
+    /// address it moves to.
+    pub address: u16,
+    /// The bytes, as a range of `Assembly::code`
+    pub bytes: Range<usize>,
+    pub span: Span,
+}
+
+/// Resolves label and constant references, checks that every operand fits
+/// in its instruction or directive and emits the bytes of the program
+pub fn code_gen(statements: &[Statement], options: &Options, warnings: &mut Errors) -> Result<Assembly, Errors> {
+    let mut errors = Errors::new();
+    let layout = layout(statements, options, &mut errors);
+    let symbols = &layout.symbols;
+    let mut code: Vec<u8> = vec![];
+    let mut map = Vec::with_capacity(statements.len());
+    // Value of every character mapped so far, by its code
+    let mut charmap: HashMap<i64, &Expr> = HashMap::new();
+
+    let mut operand = |op: &Expr, width| {
+        let value = resolve(op, symbols)?;
+        check_range(value, width, op.span, options, warnings)
+    };
+    for (i, (statement, &address)) in statements.iter().zip(&layout.addresses).enumerate() {
+        pad(&mut code, address - options.base);
+        let start = code.len();
+        let emitted = match statement.kind {
+            StatementKind::Label(_) | StatementKind::Org(_) | StatementKind::Align(_) => Ok(()),
+            // Read by the first pass, which reported any error
+            StatementKind::IncSprite { .. } | StatementKind::IncBin { .. } => {
+                if let Some(bytes) = layout.files.get(&i) {
+                    code.extend_from_slice(bytes);
+                }
+                Ok(())
+            },
+            // Evaluated here so a constant that is never used is still valid
+            StatementKind::Constant(_, ref value) => resolve(value, symbols).map(|_| ()),
+            StatementKind::Instruction(ref instruction) => {
+                instruction.clone().map(|op, width| operand(&op, width)).map(|resolved| {
+                    push_word(&mut code, resolved.encode());
+                })
+            },
+            StatementKind::Bytes(ref values) => values.iter().try_for_each(|op| {
+                operand(op, Width::Byte).map(|value| code.push(value as u8))
+            }),
+            StatementKind::Words(ref values) => values.iter().try_for_each(|op| {
+                operand(op, Width::Word).map(|value| push_word(&mut code, value))
+            }),
+            StatementKind::CharMap(ref c, ref value) => resolve(c, symbols).map(|c| {
+                charmap.insert(c, value);
+            }),
+            StatementKind::Text(ref text, width) => text.chars().try_for_each(|c| {
+                let value = charmap.get(&(c as i64)).ok_or(AssembleError::UnmappedChar { c, span: statement.span })?;
+                operand(value, width).map(|value| match width {
+                    Width::Word => push_word(&mut code, value),
+                    _ => code.push(value as u8),
+                })
+            }),
+        };
+        if let Err(err) = emitted {
+            errors.push(err);
+        }
+        let address = match statement.kind {
+            StatementKind::Constant(..) => continue,
+            StatementKind::Org(_) | StatementKind::Align(_) => layout.addresses.get(i + 1).map_or(layout.end, |&a| a),
+            _ => address,
+        };
+        map.push(Emitted { address, bytes: start..code.len(), span: statement.span });
+    }
+    pad(&mut code, layout.end - options.base);
+
+    // Labels local to a macro or repetition and anonymous labels are left
+    // out, as they cannot be referred to by name
+    let mut values = vec![];
+    for (name, symbol) in symbols.iter().filter(|&(name, _)| Stream::is_reference(name)) {
+        let value = match *symbol {
+            Symbol::Label(address, _) => Ok(address as i64),
+            Symbol::Constant(ref expr, ..) => resolve(expr, symbols),
+            Symbol::Predefined(value) => Ok(value),
+        };
+        // A constant that fails to resolve was reported above
+        if let Ok(value) = value {
+            values.push((name.clone(), value));
+        }
+    }
+    values.sort();
+
+    if errors.is_empty() {
+        Ok(Assembly { code, map, symbols: values })
+    } else {
+        errors.sort();
+        Err(errors)
+    }
+}
+
+// A name defined by the program or by its options
+#[derive(Debug, Clone)]
+enum Symbol {
+    Label(u16, Span),
+    // Resolved when it is first used, so it may refer to symbols defined
+    // later. Its value is kept once it resolves.
+    Constant(Expr, Span, Cell<Option<i64>>),
+    Predefined(i64),
+}
+
+impl Symbol {
+    fn span(&self) -> Option<Span> {
+        match *self {
+            Symbol::Label(_, span) | Symbol::Constant(_, span, _) => Some(span),
+            Symbol::Predefined(_) => None,
+        }
+    }
+}
+
+type Symbols = HashMap<String, Symbol>;
+
+// Adds a symbol to the table unless its name is taken, in which case the
+// definition that comes later in the source is reported. A predefined
+// symbol replaces one defined before it by the options.
+fn define(symbols: &mut Symbols, name: &str, symbol: Symbol, errors: &mut Errors) {
+    let (previous, span) = match (symbols.get(name), symbol.span()) {
+        (Some(previous), Some(span)) => (previous.span(), span),
+        _ => {
+            symbols.insert(name.to_owned(), symbol);
+            return;
+        }
+    };
+    let (span, previous) = match previous {
+        Some(previous) if previous > span => (previous, Some(span)),
+        _ => (span, previous),
+    };
+    errors.push(AssembleError::DuplicateSymbol { name: name.to_owned(), span, previous });
+}
+
+// Addresses assigned by the first pass
+struct Layout {
+    symbols: Symbols,
+    // Address of every statement
+    addresses: Vec<u16>,
+    // Address past the last byte of the program
+    end: u16,
+    // Bytes read from files, by the index of their statement
+    files: HashMap<usize, Vec<u8>>,
+}
+
+// First pass: assigns an address to every statement and label definition,
+// determined by the number of bytes emitted before it and by `ORG`,
+// `ALIGN`, `INCSPRITE` and `INCBIN`. Their operands can refer to any
+// constant but only to labels defined before them.
+fn layout(statements: &[Statement], options: &Options, errors: &mut Errors) -> Layout {
+    let mut symbols: Symbols = HashMap::new();
+    for &(ref name, value) in &options.defines {
+        define(&mut symbols, name, Symbol::Predefined(value), errors);
+    }
+    for statement in statements {
+        if let StatementKind::Constant(ref name, ref value) = statement.kind {
+            define(&mut symbols, name, Symbol::Constant(value.clone(), statement.span, Cell::new(None)), errors);
+        }
+    }
+
+    let mut addresses = Vec::with_capacity(statements.len());
+    let mut address = options.base;
+    let mut files = HashMap::new();
+
+    for (i, statement) in statements.iter().enumerate() {
+        addresses.push(address);
+        match statement.kind {
+            StatementKind::Label(ref name) => {
+                define(&mut symbols, name, Symbol::Label(address, statement.span), errors);
+            },
+            StatementKind::Org(ref op) => {
+                match resolve(op, &symbols).and_then(|target| within(target, 0, 0xffff, op.span)).map(|t| t as u16) {
+                    Ok(target) if target >= address => address = target,
+                    Ok(target) => {
+                        errors.push(AssembleError::BackwardOrigin { address: target, current: address, span: op.span });
                     },
-                    _ => {
-                        return Err(Token::IVal(nl));
-                    }
+                    Err(err) => errors.push(err),
                 }
-                last_token = temp_last_token;
             },
-            &Token::St(nl) => {
-                match curr_opcode {
-                    Some(&Mnemonic::Ld) => {
-                        if last_token.is_some() {
-                            return Err(Token::St(nl));
-                        }
-                        temp_last_token = Some(TokenRef::St(nl));
+            StatementKind::Align(ref op) => {
+                match resolve(op, &symbols).and_then(|n| within(n, 1, 0xffff, op.span)).map(|n| n as u16) {
+                    Ok(n) => address = address.saturating_add((n - address % n) % n),
+                    Err(err) => errors.push(err),
+                }
+            },
+            StatementKind::IncSprite { ref path, ref x, ref y, ref width, ref height } => {
+                match load_sprite(path, [x, y, width, height], &symbols, statement.span) {
+                    Ok(bytes) => {
+                        address = address.saturating_add(bytes.len() as u16);
+                        files.insert(i, bytes);
                     },
-                    _ => {
-                        return Err(Token::St(nl));
-                    }
+                    Err(err) => errors.push(err),
                 }
-                last_token = temp_last_token;
             },
-            &Token::Dt(nl) => {
-                match curr_opcode {
-                    Some(&Mnemonic::Ld) => {
-                        if last_token.is_some() {
-                            return Err(Token::Dt(nl));
-                        }
-                        temp_last_token = Some(TokenRef::Dt(nl));
+            StatementKind::IncBin { ref path, ref offset, ref length } => {
+                match load_binary(path, offset.as_ref(), length.as_ref(), &symbols, statement.span) {
+                    Ok(bytes) => {
+                        address = address.saturating_add(bytes.len().min(0xffff) as u16);
+                        files.insert(i, bytes);
                     },
-                    _ => {
-                        return Err(Token::Dt(nl));
-                    }
+                    Err(err) => errors.push(err),
+                }
+            },
+            _ => {
+                address = address.saturating_add(statement.size());
+            }
+        }
+    }
+    Layout { symbols, addresses, end: address, files }
+}
+
+// Reads the image of an `INCSPRITE` and cuts out the region given by its
+// operands
+fn load_sprite(path: &str, region: [&Expr; 4], symbols: &Symbols, span: Span) -> Result<Vec<u8>, AssembleError> {
+    let mut values = [0; 4];
+    for (value, op) in values.iter_mut().zip(&region) {
+        *value = resolve(op, symbols).and_then(|v| within(v, 0, 0xffff, op.span))? as usize;
+    }
+    let [x, y, width, height] = values;
+    bitmap::load_sprite(path, x, y, width, height).map_err(|error| match error {
+        BitmapError::TooLarge { width, height } => AssembleError::SpriteSize { width, height, span },
+        error => AssembleError::Image { path: path.to_owned(), error, span },
+    })
+}
+
+// Reads the part of a file an `INCBIN` embeds, from `offset` to the end of
+// the file unless a length is given
+fn load_binary(path: &str, offset: Option<&Expr>, length: Option<&Expr>, symbols: &Symbols, span: Span)
+    -> Result<Vec<u8>, AssembleError>
+{
+    let mut bytes = fs::read(path).map_err(|error| {
+        AssembleError::ReadFile { path: path.to_owned(), error, span }
+    })?;
+    let size = bytes.len() as i64;
+    let offset = match offset {
+        Some(op) => resolve(op, symbols).and_then(|n| within(n, 0, size, op.span))?,
+        None => 0,
+    };
+    let length = match length {
+        Some(op) => resolve(op, symbols).and_then(|n| within(n, 0, size - offset, op.span))?,
+        None => size - offset,
+    };
+    bytes.truncate((offset + length) as usize);
+    Ok(bytes.split_off(offset as usize))
+}
+
+// Fills the program with zeros up to the given offset
+fn pad(code: &mut Vec<u8>, offset: u16) {
+    if code.len() < offset as usize {
+        code.resize(offset as usize, 0);
+    }
+}
+
+// Emits a word in big-endian order, as Chip-8 expects
+fn push_word(code: &mut Vec<u8>, word: u16) {
+    code.push((word >> 8) as u8);
+    code.push(word as u8);
+}
+
+// Second pass: evaluates an expression, replacing a label reference with
+// the address of the label and a constant with its value
+fn resolve(expr: &Expr, symbols: &Symbols) -> Result<i64, AssembleError> {
+    resolve_nested(expr, symbols, &mut vec![])
+}
+
+// `resolving` holds the constants being resolved, outermost first. A
+// constant found among them is defined in terms of itself.
+fn resolve_nested<'a>(expr: &Expr, symbols: &'a Symbols, resolving: &mut Vec<&'a str>)
+    -> Result<i64, AssembleError>
+{
+    expr.eval(&mut |name, span| {
+        match symbols.get_key_value(name) {
+            Some((_, &Symbol::Label(address, _))) => Ok(address as i64),
+            Some((_, &Symbol::Predefined(value))) => Ok(value),
+            Some((name, Symbol::Constant(value, _, cached))) => {
+                if let Some(value) = cached.get() {
+                    return Ok(value);
+                }
+                if resolving.contains(&name.as_str()) {
+                    return Err(AssembleError::RecursiveSymbol { name: name.clone(), span });
+                }
+                if resolving.len() >= MAX_SYMBOL_DEPTH {
+                    return Err(AssembleError::SymbolTooDeep { name: name.clone(), span });
+                }
+                resolving.push(name);
+                let value = resolve_nested(value, symbols, resolving);
+                resolving.pop();
+                if let Ok(value) = value {
+                    cached.set(Some(value));
                 }
-                last_token = temp_last_token;
+                value
             },
-			&Token::Comment(_) => {}
+            None => Err(AssembleError::UndefinedSymbol { name: name.to_owned(), span }),
+        }
+    })
+}
+
+// Checks that a value lies in `min..=max`
+fn within(value: i64, min: i64, max: i64, span: Span) -> Result<i64, AssembleError> {
+    if (min..=max).contains(&value) {
+        Ok(value)
+    } else {
+        Err(AssembleError::OperandOutOfRange { value, min, max, span })
+    }
+}
+
+// Rejects a value that does not fit in the operand it is given as, or masks
+// it to the operand's width with a warning when truncation is enabled.
+// Negative values are stored in two's complement.
+fn check_range(value: i64, width: Width, span: Span, options: &Options, warnings: &mut Errors) -> Result<u16, AssembleError> {
+    let masked = (value & width.max() as i64) as u16;
+    match within(value, width.min(), width.max() as i64, span) {
+        Ok(_) => Ok(masked),
+        Err(err) if options.truncate => {
+            warnings.push(err);
+            Ok(masked)
+        },
+        Err(err) => Err(err),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+    use std::process;
+
+    use super::*;
+
+    fn assemble_source(source: &str) -> Result<Vec<u8>, Errors> {
+        let mut stream = Stream::from(source);
+        let result = assemble(&mut stream, &Options::default(), &mut Files::new(), &mut Errors::new());
+        result.map(|assembly| assembly.code)
+    }
+
+    #[test]
+    fn assembles_every_instruction() {
+        let table: &[(&str, u16)] = &[
+            ("CLS", 0x00e0),
+            ("RET", 0x00ee),
+            ("SYS 0x123", 0x0123),
+            ("JP 0x234", 0x1234),
+            ("CALL 0x345", 0x2345),
+            ("SE V1, 0x42", 0x3142),
+            ("SNE V2, 0x42", 0x4242),
+            ("SE V3, V4", 0x5340),
+            ("LD V5, 0xff", 0x65ff),
+            ("ADD V6, 1", 0x7601),
+            ("LD V7, V8", 0x8780),
+            ("OR V9, VA", 0x89a1),
+            ("AND VB, VC", 0x8bc2),
+            ("XOR VD, VE", 0x8de3),
+            ("ADD VF, V0", 0x8f04),
+            ("SUB V1, V2", 0x8125),
+            ("SHR V3, V4", 0x8346),
+            ("SUBN V5, V6", 0x8567),
+            ("SHL V7, V8", 0x878e),
+            ("SNE V9, VA", 0x99a0),
+            ("LD I, 0x456", 0xa456),
+            ("JP V0, 0x567", 0xb567),
+            ("RND VB, 0x0f", 0xcb0f),
+            ("DRW VC, VD, 5", 0xdcd5),
+            ("SKP VE", 0xee9e),
+            ("SKNP VF", 0xefa1),
+            ("LD V1, DT", 0xf107),
+            ("LD V2, K", 0xf20a),
+            ("LD DT, V3", 0xf315),
+            ("LD ST, V4", 0xf418),
+            ("ADD I, V5", 0xf51e),
+            ("LD F, V6", 0xf629),
+            ("LD B, V7", 0xf733),
+            ("LD [I], V8", 0xf855),
+            ("LD V9, [I]", 0xf965),
+            ("SHR VA", 0x8aa6),
+            ("SHL VB", 0x8bbe),
+        ];
+        for &(source, opcode) in table {
+            match assemble_source(source) {
+                Ok(code) => assert_eq!(code, vec![(opcode >> 8) as u8, opcode as u8], "{}", source),
+                Err(errors) => panic!("{}: {}", source, errors),
+            }
+        }
+    }
+
+    #[test]
+    fn resolves_labels() {
+        let code = assemble_source("start: JP end\nCALL start\nend: JP start\n").unwrap();
+        assert_eq!(code, vec![0x12, 0x04, 0x22, 0x00, 0x12, 0x00]);
+
+        let errors = assemble_source("loop: CLS\nJP missing\nloop: RET\n").unwrap_err();
+        let errors: Vec<_> = errors.into_iter().collect();
+        assert_eq!(errors.len(), 2);
+        assert!(matches!(errors[0], AssembleError::UndefinedSymbol { ref name, span: Span { line: 2, column: 4, .. } }
+                         if name == "missing"));
+        assert!(matches!(errors[1], AssembleError::DuplicateSymbol { ref name, span: Span { line: 3, .. },
+                                                                     previous: Some(Span { line: 1, .. }) }
+                         if name == "loop"));
+    }
+
+    #[test]
+    fn reports_every_error() {
+        let source = "FOO V0\n\
+                      bad: LD V0, ??\n\
+                      CALL bad\n\
+                      LD V1, 0x1g\n\
+                      JP nowhere\n";
+        let errors = assemble_source(source).unwrap_err();
+        assert_eq!(errors.len(), 4);
+        // Errors come in the order of the lines, whichever pass found them,
+        // and the label of a dropped line is still defined
+        let lines: Vec<_> = errors.iter().map(|err| err.span().map(|span| span.line)).collect();
+        assert_eq!(lines, [Some(1), Some(2), Some(4), Some(5)]);
+        let errors: Vec<_> = errors.into_iter().collect();
+        assert!(matches!(errors[0], AssembleError::UnknownMnemonic { .. }));
+        assert!(matches!(errors[1], AssembleError::InvalidToken { .. }));
+        assert!(matches!(errors[2], AssembleError::InvalidLiteral { .. }));
+        assert!(matches!(errors[3], AssembleError::UndefinedSymbol { ref name, .. } if name == "nowhere"));
+    }
+
+    #[test]
+    fn parses_instructions() {
+        let mut errors = Errors::new();
+        let tokens = tokenize(&mut Stream::from("start: DRW V0, V1, n + 1\nJP start\n"), &mut errors);
+        let statements = parse(&tokens, &mut errors);
+        assert!(errors.is_empty());
+        assert_eq!(statements.len(), 3);
+
+        let at = |line, column, start, end| Span { line, column, start, end, ..Span::default() };
+        assert!(matches!(statements[0].kind, StatementKind::Label(ref name) if name == "start"));
+        assert_eq!(statements[0].span, at(1, 1, 0, 6));
+        let n = Expr { kind: ExprKind::Symbol("n".to_owned()), span: at(1, 20, 19, 20) };
+        let one = Expr { kind: ExprKind::Number(1), span: at(1, 24, 23, 24) };
+        let sum = Expr { kind: ExprKind::Binary(Operator::Plus, Box::new(n), Box::new(one)), span: at(1, 20, 19, 24) };
+        match statements[1].kind {
+            StatementKind::Instruction(ref instruction) => {
+                assert_eq!(*instruction, Instruction::Drw { x: Register::V0, y: Register::V1, n: sum });
+            },
+            ref other => panic!("expected DRW, found {:?}", other),
+        }
+        match statements[2].kind {
+            StatementKind::Instruction(Instruction::Jp { ref addr }) => {
+                assert_eq!(*addr, Expr { kind: ExprKind::Symbol("start".to_owned()), span: at(2, 4, 28, 33) });
+            },
+            ref other => panic!("expected JP, found {:?}", other),
+        }
+    }
+
+    #[test]
+    fn reports_out_of_range_operands() {
+        let source = "LD V0, 300\nJP 0x1234\nDRW V0, V1, 20\nLD V1, -129\n";
+        let errors: Vec<_> = assemble_source(source).unwrap_err().into_iter().collect();
+        assert_eq!(errors.len(), 4);
+        assert!(matches!(errors[0], AssembleError::OperandOutOfRange { value: 300, min: -128, max: 0xff, .. }));
+        assert!(matches!(errors[1], AssembleError::OperandOutOfRange { value: 0x1234, min: 0, max: 0xfff, .. }));
+        assert!(matches!(errors[2], AssembleError::OperandOutOfRange { value: 20, min: -8, max: 0xf,
+                                                                       span: Span { line: 3, column: 13, .. } }));
+        assert!(matches!(errors[3], AssembleError::OperandOutOfRange { value: -129, .. }));
+
+        let options = Options { truncate: true, ..Options::default() };
+        let mut warnings = Errors::new();
+        let assembly = assemble(&mut Stream::from(source), &options, &mut Files::new(), &mut warnings).unwrap();
+        assert_eq!(assembly.code, vec![0x60, 0x2c, 0x12, 0x34, 0xd0, 0x14, 0x61, 0x7f]);
+        assert_eq!(warnings.len(), 4);
+    }
+
+    #[test]
+    fn emits_data() {
+        let source = "JP end\n\
+                      table: DB 1, 0x02, 3\n\
+                      .word 0x1234\n\
+                      end: LD I, table\n";
+        let code = assemble_source(source).unwrap();
+        assert_eq!(code, vec![0x12, 0x07, 0x01, 0x02, 0x03, 0x12, 0x34, 0xa2, 0x02]);
+    }
+
+    #[test]
+    fn moves_location_counter() {
+        let source = "DB 1\n\
+                      ALIGN 2\n\
+                      start: CLS\n\
+                      ORG 0x208\n\
+                      end: JP start\n\
+                      DW end\n";
+        let code = assemble_source(source).unwrap();
+        assert_eq!(code, vec![0x01, 0x00, 0x00, 0xe0, 0x00, 0x00, 0x00, 0x00, 0x12, 0x02, 0x02, 0x08]);
+
+        // Labels are relative to the load address, e.g. that of ETI 660
+        // programs
+        let options = Options { base: ETI_660_LOAD_ADDRESS, ..Options::default() };
+        let mut stream = Stream::from("start: JP start\nORG 0x606\nDW start\n");
+        let assembly = assemble(&mut stream, &options, &mut Files::new(), &mut Errors::new()).unwrap();
+        assert_eq!(assembly.code, vec![0x16, 0x00, 0x00, 0x00, 0x00, 0x00, 0x06, 0x00]);
+
+        let errors: Vec<_> = assemble_source("DB 1, 2\nORG 0x201\n").unwrap_err().into_iter().collect();
+        assert!(matches!(errors[..], [AssembleError::BackwardOrigin { address: 0x201, current: 0x202, .. }]));
+    }
+
+    #[test]
+    fn resolves_constants() {
+        let source = "HEIGHT EQU 5\n\
+                      .const ROW = HEIGHT\n\
+                      DRW V0, V1, ROW\n\
+                      ORG START\n\
+                      START EQU 0x204\n\
+                      DB HEIGHT\n";
+        let code = assemble_source(source).unwrap();
+        assert_eq!(code, vec![0xd0, 0x15, 0x00, 0x00, 0x05]);
+
+        // The last of the definitions given in the options wins
+        let defines = vec![("A".to_owned(), 1), ("A".to_owned(), 2)];
+        let options = Options { defines, ..Options::default() };
+        let result = assemble(&mut Stream::from("DB A\nA EQU 3\n"), &options, &mut Files::new(), &mut Errors::new());
+        let errors: Vec<_> = result.unwrap_err().into_iter().collect();
+        assert!(matches!(errors[..], [AssembleError::DuplicateSymbol { span: Span { line: 2, .. }, previous: None, .. }]));
+        let program = ::assemble(&b"DB A\n"[..], &options).unwrap();
+        assert_eq!(program.code, vec![0x02]);
+
+        // Cycles are found however many symbols there are
+        let labels: String = (0..1000).map(|i| format!("L{}:\n", i)).collect();
+        let errors = assemble_source(&format!("{}FOO EQU BAR + 1\nBAR EQU FOO\nDB FOO\n", labels)).unwrap_err();
+        assert!(errors.iter().all(|err| matches!(err, AssembleError::RecursiveSymbol { .. })));
+        assert!(matches!(errors.iter().next(), Some(AssembleError::RecursiveSymbol { span: Span { line: 1001, .. }, .. })));
+
+        // A chain of constants resolves up to a fixed depth
+        let chain = |n: usize| -> String {
+            let source: String = (0..n).map(|i| format!("C{} EQU C{} + 1\n", i, i + 1)).collect();
+            source + &format!("C{} EQU 0\nDB C0\n", n)
+        };
+        assert_eq!(assemble_source(&chain(MAX_SYMBOL_DEPTH - 1)).unwrap(), vec![MAX_SYMBOL_DEPTH as u8 - 1]);
+        let errors = assemble_source(&chain(3000)).unwrap_err();
+        assert!(errors.iter().all(|err| matches!(err, AssembleError::SymbolTooDeep { .. })));
+    }
+
+    #[test]
+    fn evaluates_expressions() {
+        let source = "WIDTH EQU 64\n\
+                      LD V0, (WIDTH - 8) / 2\n\
+                      LD I, sprite + 5*3\n\
+                      DB hi(sprite), lo(sprite), 1 << 3 | 1, ~0 & 0xff, -(-3)\n\
+                      sprite:\n";
+        let code = assemble_source(source).unwrap();
+        assert_eq!(code, vec![0x60, 0x1c, 0xa2, 0x18, 0x02, 0x09, 0x09, 0xff, 0x03]);
+
+        let errors = assemble_source("LD V0, 1 / (2 - 2)\nLD V1, (1 + 2\n").unwrap_err();
+        let errors: Vec<_> = errors.into_iter().collect();
+        assert!(matches!(errors[0], AssembleError::DivisionByZero { .. }));
+        assert!(matches!(errors[1], AssembleError::UnclosedParen { .. }));
+
+        let errors = assemble_source("DW 0x7fff_ffff_ffff_ffff + 1\nDW 1 << 64\nDW -(-0x7fff_ffff_ffff_ffff - 1)\n");
+        let errors: Vec<_> = errors.unwrap_err().into_iter().collect();
+        assert_eq!(errors.len(), 3);
+        assert!(errors.iter().all(|err| matches!(err, AssembleError::Overflow { .. })));
+
+        // Every constant is evaluated once, however often it is used
+        let mut source: String = (0..64).map(|i| format!("C{} EQU C{1} + C{1} - C{1}\n", i, i + 1)).collect();
+        source.push_str("C64 EQU 7\nDB C0\n");
+        assert_eq!(assemble_source(&source).unwrap(), vec![7]);
+    }
+
+    #[test]
+    fn draws_sprites() {
+        let source = "LD I, ball\n\
+                      DRW V0, V1, ball_height\n\
+                      .sprite ball\n\
+                      .XX.\n\
+                      X..X ; middle\n\
+                      .XX.\n";
+        let code = assemble_source(source).unwrap();
+        assert_eq!(code, vec![0xa2, 0x04, 0xd0, 0x13, 0x60, 0x90, 0x60]);
+
+        let rows = "X.......X......X\n".repeat(16);
+        let code = assemble_source(&format!("DRW V0, V1, big_height\n.sprite big\n{}", rows)).unwrap();
+        assert_eq!(&code[..4], &[0xd0, 0x10, 0x80, 0x81]);
+        assert_eq!(code.len(), 34);
+
+        let errors = assemble_source(".sprite a\nXX\nX..\n.sprite c\n").unwrap_err();
+        let errors: Vec<_> = errors.into_iter().collect();
+        assert!(matches!(errors[0], AssembleError::SpriteRowWidth { expected: 2, found: 3, .. }));
+        assert!(matches!(errors[1], AssembleError::MissingOperand { .. }));
+        // Directives are named the way they are written
+        assert_eq!(errors[1].message(), "missing operand for .sprite");
+        let errors: Vec<_> = assemble_source(".const = 1\n.charmap 1\n").unwrap_err().into_iter().collect();
+        assert_eq!(errors[0].message(), "unexpected operand `=` for .const");
+        assert_eq!(errors[1].hint().unwrap(), format!(".charmap expects {}", Mnemonic::CharMap.operands()));
+    }
+
+    #[test]
+    fn includes_sprites() {
+        let dir = env::temp_dir();
+        let plain = dir.join(format!("c8asm-sheet-{}.pbm", process::id()));
+        let gray = dir.join(format!("c8asm-sheet-{}.pgm", process::id()));
+        fs::write(&plain, "P1\n# sheet\n4 2\n0110\n1001\n").unwrap();
+        fs::write(&gray, [&b"P5 2 2 255\n"[..], &[0, 200, 255, 10]].concat()).unwrap();
+
+        let source = format!("INCSPRITE \"{}\", 1, 0, 3, 2\n\
+                              INCSPRITE \"{}\", 0, 0, 2, 2\n\
+                              LD I, end\n\
+                              end:\n", plain.display(), gray.display());
+        let result = assemble_source(&source);
+        let errors = assemble_source(&format!("INCSPRITE \"{0}\", 2, 0, 4, 2\n\
+                                               INCSPRITE \"{0}\", 0, 0, 9, 2\n\
+                                               INCSPRITE \"missing.pbm\", 0, 0, 1, 1\n",
+                                              plain.display()));
+        fs::remove_file(&plain).unwrap();
+        fs::remove_file(&gray).unwrap();
+
+        assert_eq!(result.unwrap(), vec![0xc0, 0x20, 0x80, 0x40, 0xa2, 0x06]);
+        let errors: Vec<_> = errors.unwrap_err().into_iter().collect();
+        assert!(matches!(errors[0], AssembleError::Image { error: BitmapError::OutOfBounds { .. }, .. }));
+        assert!(matches!(errors[1], AssembleError::SpriteSize { width: 9, height: 2, .. }));
+        assert!(matches!(errors[2], AssembleError::Image { error: BitmapError::Io(_), .. }));
+    }
+
+    #[test]
+    fn includes_files() {
+        let dir = env::temp_dir().join(format!("c8asm-include-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("lib.asm"), "table: INCBIN \"data.bin\", 1, 2\nlast: INCBIN \"data.bin\", 3\n").unwrap();
+        fs::write(dir.join("data.bin"), [1, 2, 3, 4, 5]).unwrap();
+        fs::write(dir.join("loop.asm"), "INCLUDE \"loop.asm\"\n").unwrap();
+
+        let dir_name = dir.display();
+        let result = assemble_source(&format!("LD I, last\nINCLUDE \"{}/lib.asm\"\n", dir_name));
+        let errors = assemble_source(&format!("INCLUDE \"{0}/loop.asm\"\n\
+                                               INCLUDE \"{0}/missing.asm\"\n\
+                                               INCBIN \"{0}/data.bin\", 6\n", dir_name));
+        // Files not next to the input are searched in the include directories
+        let options = Options { include_dirs: vec![dir.clone()], ..Options::default() };
+        let mut stream = Stream::from("INCLUDE \"lib.asm\"\nLD I, table\n");
+        let found = assemble(&mut stream, &options, &mut Files::new(), &mut Errors::new());
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(result.unwrap(), vec![0xa2, 0x04, 0x02, 0x03, 0x04, 0x05]);
+        assert_eq!(found.unwrap().code, vec![0x02, 0x03, 0x04, 0x05, 0xa2, 0x00]);
+        // Errors in an included file come where it is included
+        let errors: Vec<_> = errors.unwrap_err().into_iter().collect();
+        assert!(matches!(errors[0], AssembleError::IncludeCycle { ref span, .. } if span.file == 1));
+        assert!(matches!(errors[1], AssembleError::ReadFile { .. }));
+        assert!(matches!(errors[2], AssembleError::OperandOutOfRange { value: 6, max: 5, .. }));
+    }
+
+    #[test]
+    fn expands_macros() {
+        let source = "MACRO wait reg, n\n\
+                      LD reg, n\n\
+                      loop: ADD reg, -1\n\
+                      SE reg, 0\n\
+                      JP loop\n\
+                      ENDM\n\
+                      wait V1, (2 + 1)\n\
+                      wait V2, 4\n";
+        let code = assemble_source(source).unwrap();
+        assert_eq!(code, vec![0x61, 0x03, 0x71, 0xff, 0x31, 0x00, 0x12, 0x02,
+                              0x62, 0x04, 0x72, 0xff, 0x32, 0x00, 0x12, 0x0a]);
+
+        let source = "MACRO again\nagain\nENDM\nagain\nwait V1\nMACRO wait reg\nENDM\nwait\nENDM\nMACRO open\n";
+        let errors: Vec<_> = assemble_source(source).unwrap_err().into_iter().collect();
+        assert!(matches!(errors[0], AssembleError::MacroRecursion { .. }));
+        assert!(matches!(errors[1], AssembleError::UnknownMnemonic { .. }));
+        assert!(matches!(errors[2], AssembleError::MacroArguments { expected: 1, found: 0, .. }));
+        assert!(matches!(errors[3], AssembleError::UnmatchedBlockEnd { .. }));
+        assert!(matches!(errors[4], AssembleError::UnclosedBlock { .. }));
+
+        // A macro using itself twice is reported once rather than expanded
+        // 2^32 times
+        let errors = assemble_source("MACRO twice\ntwice\ntwice\nENDM\ntwice\n").unwrap_err();
+        assert_eq!(errors.len(), 1);
+        assert!(matches!(errors.iter().next(), Some(AssembleError::MacroRecursion { .. })));
+        let source = "MACRO empty\nENDM\nREPT 0x10000\nREPT 4\nempty\nENDR\nENDR\n";
+        let errors: Vec<_> = assemble_source(source).unwrap_err().into_iter().collect();
+        assert!(matches!(errors[..], [AssembleError::TooManyExpansions { .. }]));
+        // However large the blocks repeated in one another
+        let source = "REPT 0x10000\nREPT 0x10000\nDB 0, 0, 0, 0\nENDR\nENDR\n";
+        let errors: Vec<_> = assemble_source(source).unwrap_err().into_iter().collect();
+        assert!(matches!(errors[..], [AssembleError::TooManyExpansions { span: Span { line: 2, .. } }]));
+    }
+
+    #[test]
+    fn assembles_conditionally() {
+        let source = "MODE EQU 2\n\
+                      IF MODE == 1\n\
+                      DB 1\n\
+                      ELSEIF MODE >= 2 & MODE != 3\n\
+                      DB 2\n\
+                      IFNDEF DEBUG\n\
+                      DB 3\n\
+                      ENDIF\n\
+                      ELSE\n\
+                      DB 4\n\
+                      ENDIF\n\
+                      IFDEF MODE\n\
+                      DB 5\n\
+                      ENDIF\n";
+        let code = assemble_source(source).unwrap();
+        assert_eq!(code, vec![0x02, 0x03, 0x05]);
+        let code = assemble_source("IFDEF SCHIP\nINCLUDE \"schip-missing.asm\"\nENDIF\nCLS\n").unwrap();
+        assert_eq!(code, vec![0x00, 0xe0]);
+
+        let errors = assemble_source("IF 1\nELSE\nELSE\nENDIF\nENDIF\nIFDEF 3\nENDIF\nIF 0\n").unwrap_err();
+        let errors: Vec<_> = errors.into_iter().collect();
+        assert!(matches!(errors[0], AssembleError::BranchAfterElse { .. }));
+        assert!(matches!(errors[1], AssembleError::UnmatchedBlockEnd { .. }));
+        assert!(matches!(errors[2], AssembleError::WrongOperand { .. }));
+        assert!(matches!(errors[3], AssembleError::UnclosedBlock { span: Span { line: 8, .. }, .. }));
+    }
+
+    #[test]
+    fn repeats_blocks() {
+        let source = "COUNT EQU 2\n\
+                      REPT COUNT\n\
+                      next: DB 0xaa\n\
+                      JP next\n\
+                      ENDR\n\
+                      FOR n = 1 TO 3\n\
+                      REPT n\n\
+                      DB n * 0x10\n\
+                      ENDR\n\
+                      ENDR\n\
+                      REPT 0\n\
+                      DB 0xff\n\
+                      ENDR\n";
+        let code = assemble_source(source).unwrap();
+        assert_eq!(code, vec![0xaa, 0x12, 0x00, 0xaa, 0x12, 0x03,
+                              0x10, 0x20, 0x20, 0x30, 0x30, 0x30]);
+        let code = assemble_source("FOR i = 0 TO 3\nDB i\nENDR\nFOR dt = 1 TO 2\nDB DT, dt\nENDR\n").unwrap();
+        assert_eq!(code, vec![0x00, 0x01, 0x02, 0x03, 0x01, 0x01, 0x02, 0x02]);
+        let errors: Vec<_> = assemble_source("FOR v0 = 0 TO 1\nENDR\n").unwrap_err().into_iter().collect();
+        assert!(matches!(errors[..], [AssembleError::WrongOperand { .. }]));
+
+        let errors = assemble_source("ENDR\nFOR n = 1 2\nENDR\nREPT -1\nENDR\nREPT 1\n").unwrap_err();
+        let errors: Vec<_> = errors.into_iter().collect();
+        assert!(matches!(errors[0], AssembleError::UnmatchedBlockEnd { .. }));
+        assert!(matches!(errors[1], AssembleError::WrongOperand { .. }));
+        assert!(matches!(errors[2], AssembleError::OperandOutOfRange { .. }));
+        assert!(matches!(errors[3], AssembleError::UnclosedBlock { span: Span { line: 6, .. }, .. }));
+    }
+
+    #[test]
+    fn scopes_labels() {
+        let source = "main: JP .loop\n\
+                      .loop: -: JP -\n\
+                      JP +\n\
+                      JP ++\n\
+                      +: -: JP --\n\
+                      +: JP other.loop\n\
+                      DW .loop\n\
+                      other:\n\
+                      .loop: JP main.loop\n";
+        let code = assemble_source(source).unwrap();
+        assert_eq!(code, vec![0x12, 0x02, 0x12, 0x02, 0x12, 0x08, 0x12, 0x0a,
+                              0x12, 0x02, 0x12, 0x0e, 0x02, 0x02, 0x12, 0x02]);
+
+        let source = "a: .x: b: .x: c: JP .x\nJP .y\nJP +\nJP --\n-: RET\n";
+        let errors: Vec<_> = assemble_source(source).unwrap_err().into_iter().collect();
+        assert!(matches!(errors[0], AssembleError::UndefinedLocalLabel { ref others, .. } if others.len() == 2));
+        assert!(matches!(errors[1], AssembleError::UndefinedLocalLabel { ref others, .. } if others.is_empty()));
+        assert!(matches!(errors[2], AssembleError::MissingAnonymousLabel { found: 0, .. }));
+        assert!(matches!(errors[3], AssembleError::MissingAnonymousLabel { found: 0, .. }));
+        assert_eq!(errors.len(), 4);
+    }
+
+    #[test]
+    fn maps_text() {
+        let source = ".charmap 'A', 10\n\
+                      .charmap 'B', 'A' + 1\n\
+                      .text \"ABBA\"\n\
+                      .charmap ' ', font\n\
+                      .textw \"A \"\n\
+                      font: .ascii \"B\"\n";
+        let code = assemble_source(source).unwrap();
+        assert_eq!(code, vec![0x0a, 0x42, 0x42, 0x0a, 0x00, 0x0a, 0x02, 0x08, 0x42]);
+
+        let errors = assemble_source(".text \"A\"\n.charmap 'A', 1\n.textw 'A'\n").unwrap_err();
+        let errors: Vec<_> = errors.into_iter().collect();
+        assert!(matches!(errors[0], AssembleError::UnmappedChar { c: 'A', .. }));
+        assert!(matches!(errors[1], AssembleError::WrongOperand { .. }));
+    }
+
+    #[test]
+    fn iterates_tokens() {
+        let tokens: Vec<_> = Stream::from("loop: ld V0, 0x0A ; wait\n  ?? JP loop").collect();
+        let texts: Vec<_> = tokens.iter().map(|t| t.as_ref().map(Token::text).map_err(|e| e.span())).collect();
+        assert_eq!(texts[..5], [Ok("loop:"), Ok("ld"), Ok("V0"), Ok(","), Ok("0x0A")]);
+        assert!(matches!(tokens[4], Ok(Token { kind: TokenKind::ImmConst(10), span: Span { column: 14, .. }, .. })));
+        assert_eq!(texts[5], Ok("; wait"));
+        assert!(matches!(tokens[6], Err(LexError::InvalidToken { ref text, .. }) if text == "??"));
+        assert_eq!(texts[7..], [Ok("JP"), Ok("loop")]);
+
+        // Names are the text of their token, read from the source
+        let tokens: Vec<Token> = Stream::from("-: .loop: DB x, \"a\\\"b\" ..XX").map(Result::unwrap).collect();
+        let names: Vec<_> = tokens.iter().map(|t| (t.kind, t.name())).collect();
+        assert_eq!(names, [(TokenKind::Label, "-"), (TokenKind::Label, ".loop"), (TokenKind::Opcode(Mnemonic::Db), "DB"),
+                           (TokenKind::Ident, "x"), (TokenKind::Comma, ","), (TokenKind::Str, "a\\\"b"),
+                           (TokenKind::Pixels, "..XX")]);
+        assert_eq!(unescape_str(tokens[5].name()), "a\"b");
+    }
+
+    #[test]
+    fn reports_read_errors() {
+        struct Failing;
+        impl Read for Failing {
+            fn read(&mut self, _: &mut [u8]) -> io::Result<usize> {
+                Err(io::Error::other("disconnected"))
+            }
+        }
+        let mut errors = Errors::new();
+        assert!(tokenize(&mut Stream::new(Failing), &mut errors).is_empty());
+        let errors: Vec<_> = errors.into_iter().collect();
+        assert!(matches!(errors[..], [AssembleError::Io(_)]));
+    }
+
+    #[test]
+    fn handles_line_endings_and_encodings() {
+        let program = ::assemble_str("\u{feff}CLS\r\nLD\u{a0}V0, 1 ; one\rRET\r\n").unwrap();
+        assert_eq!(program.code, vec![0x00, 0xe0, 0x60, 0x01, 0x00, 0xee]);
+
+        let tokens: Vec<_> = Stream::new(&b"CLS\r\n\"\xc3\xa9\" ?\rRET x\xffy"[..]).collect();
+        assert!(matches!(tokens[1], Ok(Token { span: Span { line: 2, column: 1, .. }, .. })));
+        assert!(matches!(tokens[2], Err(LexError::InvalidToken { span: Span { line: 2, column: 5, .. }, .. })));
+        assert!(matches!(tokens[3], Ok(Token { span: Span { line: 3, column: 1, .. }, .. })));
+        assert!(matches!(tokens[4], Err(LexError::InvalidUtf8 { span: Span { line: 3, column: 6, .. } })));
+        assert_eq!(tokens.len(), 5);
+    }
+
+    #[test]
+    fn parses_literals() {
+        let source = "DB 0b1010_0101, 0o17, $FF, #fe, 'A', ' ', ',', '\\'', 1_000 - 990\n\
+                      LD V0, -1\n\
+                      DRW V0, V1, -8\n";
+        let code = assemble_source(source).unwrap();
+        assert_eq!(code, vec![0xa5, 0x0f, 0xff, 0xfe, 0x41, 0x20, 0x2c, 0x27, 0x0a, 0x60, 0xff, 0xd0, 0x18]);
+
+        for literal in &["0x1g", "0b102", "'AB'", "1_", "99999999999999999999"] {
+            let errors = assemble_source(&format!("DB {}\n", literal)).unwrap_err();
+            assert!(errors.iter().all(|err| matches!(err, AssembleError::InvalidLiteral { .. })), "{}", literal);
         }
+        // Addresses are never negative
+        let errors: Vec<_> = assemble_source("JP -1\nLD I, -5\n").unwrap_err().into_iter().collect();
+        assert!(matches!(errors[0], AssembleError::OperandOutOfRange { value: -1, min: 0, max: 0xfff, .. }));
+        assert!(matches!(errors[1], AssembleError::OperandOutOfRange { value: -5, min: 0, max: 0xfff, .. }));
     }
-    Ok(result)
 }